@@ -0,0 +1,46 @@
+//! Times how long the release build takes to render
+//! `examples/twinkle.json`. Run with `cargo bench`.
+//!
+//! The arrangement is copied to a temporary directory and rendered there,
+//! so any `twinkle.wav` already in `examples/` is left alone.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 10;
+
+fn main() {
+    let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let dir = env::temp_dir().join(format!("euphonium-bench-{}", process::id()));
+    fs::create_dir_all(&dir).expect("failed to create a temporary directory");
+    for file in &["twinkle.json", "twinkle.mid"] {
+        fs::copy(examples.join(file), dir.join(file)).expect("failed to copy the example");
+    }
+
+    let mut total = Duration::new(0, 0);
+    let mut fastest = Duration::new(u64::MAX, 0);
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let status = Command::new(env!("CARGO_BIN_EXE_euphonium"))
+            .arg("twinkle.json")
+            .current_dir(&dir)
+            .status()
+            .expect("failed to run euphonium");
+        let elapsed = start.elapsed();
+        assert!(status.success(), "euphonium exited with {}", status);
+
+        total += elapsed;
+        fastest = fastest.min(elapsed);
+    }
+    let _ = fs::remove_dir_all(&dir);
+
+    println!(
+        "render twinkle.json: mean {:?}, fastest {:?} over {} runs",
+        total / RUNS,
+        fastest,
+        RUNS
+    );
+}
@@ -0,0 +1,215 @@
+use easer::functions::Easing as _;
+use easer::functions::{Back, Bounce, Circ, Cubic, Elastic, Expo, Quad, Quart, Quint, Sine};
+
+use std::fmt;
+use std::str::FromStr;
+
+pub use crate::error::EasingError;
+
+/// Which end of an easing curve changes slowly.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Direction {
+    In,
+    Out,
+    InOut,
+}
+
+/// How an envelope phase moves from its start value to its end value.
+///
+/// Easing functions are named in arrangements as in `"SineInOut"`, with
+/// any argument in brackets, as in `"PowerIn(2.5)"` or `"Steps(4)"`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Easing {
+    Linear,
+    Back(Direction),
+    Bounce(Direction),
+    Circ(Direction),
+    Cubic(Direction),
+    Elastic(Direction),
+    Expo(Direction),
+    Quad(Direction),
+    Quart(Direction),
+    Quint(Direction),
+    Sine(Direction),
+    /// `t` raised to the given exponent, like `Quad` for an exponent of 2
+    /// but with any positive exponent.
+    Power(f32, Direction),
+    /// A symmetric S-curve, `t^k / (t^k + (1-t)^k)`, which is linear when
+    /// `k` is 1 and steeper in the middle as `k` increases. Defaults to a
+    /// `k` of 2.
+    SCurve(f32),
+    /// Jumps in the given number of equal steps, holding the start value
+    /// for the first step and reaching the end value at the end.
+    Steps(u32),
+}
+
+/// A name, and the easing function it gives in each direction.
+type Family = (&'static str, fn(Direction) -> Easing);
+
+/// The easing functions with a direction, which are named by adding
+/// `In`, `Out` or `InOut` to the family's name.
+const FAMILIES: [Family; 10] = [
+    ("Back", Easing::Back),
+    ("Bounce", Easing::Bounce),
+    ("Circ", Easing::Circ),
+    ("Cubic", Easing::Cubic),
+    ("Elastic", Easing::Elastic),
+    ("Expo", Easing::Expo),
+    ("Quad", Easing::Quad),
+    ("Quart", Easing::Quart),
+    ("Quint", Easing::Quint),
+    ("Sine", Easing::Sine),
+];
+
+impl Easing {
+    /// Interpolate from `start` to `start + delta`, `frame` samples into
+    /// a phase `duration` samples long.
+    pub fn apply(self, frame: f32, start: f32, delta: f32, duration: f32) -> f32 {
+        use self::Direction::*;
+        match self {
+            Easing::Linear => start + delta / (duration / frame),
+            Easing::Back(In) => Back::ease_in(frame, start, delta, duration),
+            Easing::Back(Out) => Back::ease_out(frame, start, delta, duration),
+            Easing::Back(InOut) => Back::ease_in_out(frame, start, delta, duration),
+            Easing::Bounce(In) => Bounce::ease_in(frame, start, delta, duration),
+            Easing::Bounce(Out) => Bounce::ease_out(frame, start, delta, duration),
+            Easing::Bounce(InOut) => Bounce::ease_in_out(frame, start, delta, duration),
+            Easing::Circ(In) => Circ::ease_in(frame, start, delta, duration),
+            Easing::Circ(Out) => Circ::ease_out(frame, start, delta, duration),
+            Easing::Circ(InOut) => Circ::ease_in_out(frame, start, delta, duration),
+            Easing::Cubic(In) => Cubic::ease_in(frame, start, delta, duration),
+            Easing::Cubic(Out) => Cubic::ease_out(frame, start, delta, duration),
+            Easing::Cubic(InOut) => Cubic::ease_in_out(frame, start, delta, duration),
+            Easing::Elastic(In) => Elastic::ease_in(frame, start, delta, duration),
+            Easing::Elastic(Out) => Elastic::ease_out(frame, start, delta, duration),
+            Easing::Elastic(InOut) => Elastic::ease_in_out(frame, start, delta, duration),
+            Easing::Expo(In) => Expo::ease_in(frame, start, delta, duration),
+            Easing::Expo(Out) => Expo::ease_out(frame, start, delta, duration),
+            Easing::Expo(InOut) => Expo::ease_in_out(frame, start, delta, duration),
+            Easing::Quad(In) => Quad::ease_in(frame, start, delta, duration),
+            Easing::Quad(Out) => Quad::ease_out(frame, start, delta, duration),
+            Easing::Quad(InOut) => Quad::ease_in_out(frame, start, delta, duration),
+            Easing::Quart(In) => Quart::ease_in(frame, start, delta, duration),
+            Easing::Quart(Out) => Quart::ease_out(frame, start, delta, duration),
+            Easing::Quart(InOut) => Quart::ease_in_out(frame, start, delta, duration),
+            Easing::Quint(In) => Quint::ease_in(frame, start, delta, duration),
+            Easing::Quint(Out) => Quint::ease_out(frame, start, delta, duration),
+            Easing::Quint(InOut) => Quint::ease_in_out(frame, start, delta, duration),
+            Easing::Sine(In) => Sine::ease_in(frame, start, delta, duration),
+            Easing::Sine(Out) => Sine::ease_out(frame, start, delta, duration),
+            Easing::Sine(InOut) => Sine::ease_in_out(frame, start, delta, duration),
+            Easing::Power(..) | Easing::SCurve(_) | Easing::Steps(_) => {
+                start + delta * self.shape(frame / duration)
+            }
+        }
+    }
+
+    /// The curves which easer doesn't provide, from 0 to 1 as `t` goes
+    /// from 0 to 1.
+    fn shape(self, t: f32) -> f32 {
+        match self {
+            Easing::Power(p, Direction::In) => t.powf(p),
+            Easing::Power(p, Direction::Out) => 1.0 - (1.0 - t).powf(p),
+            Easing::Power(p, Direction::InOut) => {
+                if t < 0.5 {
+                    (2.0 * t).powf(p) / 2.0
+                } else {
+                    1.0 - (2.0 - 2.0 * t).powf(p) / 2.0
+                }
+            }
+            Easing::SCurve(k) => {
+                let rising = t.powf(k);
+                rising / (rising + (1.0 - t).powf(k))
+            }
+            Easing::Steps(n) => (t * n as f32).floor().min(n as f32) / n as f32,
+            _ => t,
+        }
+    }
+
+    /// The names of all easing functions, for error messages.
+    fn names() -> String {
+        let mut names = vec![String::from("Linear")];
+        for (family, _) in FAMILIES.iter() {
+            for direction in &["In", "Out", "InOut"] {
+                names.push(format!("{}{}", family, direction));
+            }
+        }
+        for name in &[
+            "PowerIn(p)",
+            "PowerOut(p)",
+            "PowerInOut(p)",
+            "SCurve(k)",
+            "Steps(n)",
+        ] {
+            names.push(String::from(*name));
+        }
+        names.join(", ")
+    }
+}
+
+impl FromStr for Easing {
+    type Err = EasingError;
+
+    fn from_str(s: &str) -> Result<Easing, EasingError> {
+        let (name, arg) = match s.find('(') {
+            Some(i) if s.ends_with(')') => (&s[..i], Some(s[i + 1..s.len() - 1].trim())),
+            _ => (s, None),
+        };
+        let (family, direction) = if let Some(family) = name.strip_suffix("InOut") {
+            (family, Some(Direction::InOut))
+        } else if let Some(family) = name.strip_suffix("In") {
+            (family, Some(Direction::In))
+        } else if let Some(family) = name.strip_suffix("Out") {
+            (family, Some(Direction::Out))
+        } else {
+            (name, None)
+        };
+        let argument = |valid: fn(f32) -> bool| match arg.map(str::parse::<f32>) {
+            Some(Ok(a)) if valid(a) => Ok(a),
+            _ => Err(EasingError::Argument(String::from(s))),
+        };
+        let unknown = || EasingError::Unknown(String::from(s), Easing::names());
+        match (family, direction) {
+            ("Linear", None) if arg.is_none() => Ok(Easing::Linear),
+            ("Power", Some(direction)) => Ok(Easing::Power(argument(|p| p > 0.0)?, direction)),
+            ("SCurve", None) if arg.is_none() => Ok(Easing::SCurve(2.0)),
+            ("SCurve", None) => Ok(Easing::SCurve(argument(|k| k > 0.0)?)),
+            ("Steps", None) => {
+                let n = argument(|n| n >= 1.0 && n.fract() == 0.0)?;
+                Ok(Easing::Steps(n as u32))
+            }
+            (family, Some(direction)) if arg.is_none() => FAMILIES
+                .iter()
+                .find(|(name, _)| *name == family)
+                .map(|(_, easing)| easing(direction))
+                .ok_or_else(unknown),
+            _ => Err(unknown()),
+        }
+    }
+}
+
+impl fmt::Display for Easing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = |d: Direction| match d {
+            Direction::In => "In",
+            Direction::Out => "Out",
+            Direction::InOut => "InOut",
+        };
+        match *self {
+            Easing::Linear => write!(f, "Linear"),
+            Easing::Power(p, d) => write!(f, "Power{}({})", direction(d), p),
+            Easing::SCurve(k) => write!(f, "SCurve({})", k),
+            Easing::Steps(n) => write!(f, "Steps({})", n),
+            Easing::Back(d) => write!(f, "Back{}", direction(d)),
+            Easing::Bounce(d) => write!(f, "Bounce{}", direction(d)),
+            Easing::Circ(d) => write!(f, "Circ{}", direction(d)),
+            Easing::Cubic(d) => write!(f, "Cubic{}", direction(d)),
+            Easing::Elastic(d) => write!(f, "Elastic{}", direction(d)),
+            Easing::Expo(d) => write!(f, "Expo{}", direction(d)),
+            Easing::Quad(d) => write!(f, "Quad{}", direction(d)),
+            Easing::Quart(d) => write!(f, "Quart{}", direction(d)),
+            Easing::Quint(d) => write!(f, "Quint{}", direction(d)),
+            Easing::Sine(d) => write!(f, "Sine{}", direction(d)),
+        }
+    }
+}
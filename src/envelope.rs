@@ -1,123 +1,580 @@
-use easer::functions::*;
+use std::cmp::Ordering;
 
-use std::f32::NAN;
+use crate::easing::Easing;
+pub use crate::error::{EnvelopeError, EnvelopeResult};
+use crate::samplegen::{Param, Params, SampleGen};
+use crate::waveform::Waveform;
 
-use crate::samplegen::{Params, SampleGen};
+// When an envelope phase starts or ends.
+#[derive(Clone, Copy)]
+pub enum PhaseTime {
+    // A fraction of the note's duration
+    Fraction(f32),
+    // Seconds from the start of the note
+    Seconds(f32),
+    // Seconds from the end of the note, which may be negative
+    FromEnd(f32),
+    // Quarter notes from the start of the note, at the note's tempo
+    Beats(f32),
+}
+
+impl PhaseTime {
+    // Which of two times comes first, if they are measured in the same way.
+    fn compare(self, other: PhaseTime) -> Option<Ordering> {
+        match (self, other) {
+            (PhaseTime::Fraction(a), PhaseTime::Fraction(b))
+            | (PhaseTime::Seconds(a), PhaseTime::Seconds(b))
+            | (PhaseTime::FromEnd(a), PhaseTime::FromEnd(b))
+            | (PhaseTime::Beats(a), PhaseTime::Beats(b)) => a.partial_cmp(&b),
+            _ => None,
+        }
+    }
+
+    fn to_samples(self, p: &Params) -> u64 {
+        match self {
+            PhaseTime::Fraction(f) => (f * p[Param::Duration]) as u64,
+            PhaseTime::Seconds(s) => (s * p[Param::Rate]) as u64,
+            PhaseTime::FromEnd(s) => (p[Param::Duration] + s * p[Param::Rate]).max(0.0) as u64,
+            PhaseTime::Beats(b) => (b * 60.0 / p[Param::Tempo] * p[Param::Rate]) as u64,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct EnvPhase {
-    pub start_time: f32,
-    pub end_time: f32,
+    pub start_time: PhaseTime,
+    pub end_time: PhaseTime,
     pub start_val: f32,
     pub delta_val: f32,
-    pub ease_fn: String,
+    pub ease_fn: Easing,
+    // Replaces the easing function with an equation over `t` (which is
+    // also `x`), from 0 at the start of the phase to 1 at the end. Its
+    // result of 0 is the start value and 1 is the start value plus delta.
+    pub curve: Option<Waveform>,
+}
+
+// What happens to an envelope after the end of the note. Once note-off has
+// passed and every phase timed after it has finished, the value eases to
+// `end_val` over `duration` seconds, and then holds `end_val`.
+#[derive(Clone)]
+pub struct Release {
+    pub duration: f32,
+    pub end_val: f32,
+    pub ease_fn: Easing,
+}
+
+// A range of phases which repeats, from the start of phase `start` to the
+// end of phase `end`. After the last repetition the envelope carries on
+// from the end of the loop.
+#[derive(Clone, Copy)]
+pub struct Loop {
+    pub start: usize,
+    pub end: usize,
+    // How many times the phases play, or None to repeat them until the end
+    // of the note
+    pub count: Option<u32>,
 }
 
 #[derive(Clone)]
 pub struct Envelope {
     phases: Vec<EnvPhase>,
+    release: Option<Release>,
+    looping: Option<Loop>,
+    // The sample of the current note at which the release starts
+    release_at: f32,
+    // The value of the envelope when the release starts
+    release_from: f32,
 }
 
 impl Envelope {
-    pub fn new(phases: Vec<EnvPhase>) -> Envelope {
-        Envelope { phases }
+    pub fn new(
+        phases: Vec<EnvPhase>,
+        release: Option<Release>,
+        looping: Option<Loop>,
+    ) -> EnvelopeResult<Envelope> {
+        check_order(&phases)?;
+        if let Some(l) = looping {
+            if l.start > l.end || l.end >= phases.len() {
+                return Err(EnvelopeError::LoopRange(l.start, l.end, phases.len()));
+            }
+            if l.count == Some(0) {
+                return Err(EnvelopeError::LoopCount);
+            }
+        }
+        Ok(Envelope {
+            phases,
+            release,
+            looping,
+            release_at: f32::INFINITY,
+            release_from: f32::NAN,
+        })
+    }
+
+    // A classic ADSR envelope: rises linearly from 0 to 1 over `attack`
+    // seconds, falls to `sustain` over `decay` seconds, and holds it until
+    // the end of the note.
+    pub fn adsr(attack: f32, decay: f32, sustain: f32, release: Option<Release>) -> Envelope {
+        let phase = |start_time, end_time, start_val: f32, end_val: f32| EnvPhase {
+            start_time,
+            end_time,
+            start_val,
+            delta_val: end_val - start_val,
+            ease_fn: Easing::Linear,
+            curve: None,
+        };
+        let held = PhaseTime::Seconds(attack + decay);
+        let phases = vec![
+            phase(
+                PhaseTime::Seconds(0.0),
+                PhaseTime::Seconds(attack),
+                0.0,
+                1.0,
+            ),
+            phase(PhaseTime::Seconds(attack), held, 1.0, sustain),
+            phase(held, PhaseTime::Fraction(1.0), sustain, sustain),
+        ];
+        Envelope {
+            phases,
+            release,
+            looping: None,
+            release_at: f32::INFINITY,
+            release_from: f32::NAN,
+        }
+    }
+
+    // How far into its phases the envelope is at `sample`, once the loop
+    // has been repeated. Each repetition starts where the previous one
+    // ended, so the last sample of the loop is never played twice.
+    fn time(&self, p: &Params, sample: u64) -> u64 {
+        let l = match self.looping {
+            Some(l) => l,
+            None => return sample,
+        };
+        let start = self.phases[l.start].start_time.to_samples(p);
+        let end = self.phases[l.end].end_time.to_samples(p);
+        if sample < start || end <= start {
+            return sample;
+        }
+        let length = end - start;
+        let repeats = match l.count {
+            Some(count) => ((sample - start) / length).min(u64::from(count) - 1),
+            None => sample.min(p[Param::Duration] as u64).saturating_sub(start) / length,
+        };
+        sample - repeats * length
+    }
+
+    // How many samples after the end of the note the phases carry on for:
+    // those timed from the end of the note, and those after a loop which
+    // repeats until the end of the note.
+    fn tail(&self, p: &Params) -> u64 {
+        let note_off = self.time(p, p[Param::Duration] as u64);
+        let after_loop = |i: usize| match self.looping {
+            Some(l) => l.count.is_none() && i > l.end,
+            None => false,
+        };
+        self.phases
+            .iter()
+            .enumerate()
+            .filter(|(i, phase)| matches!(phase.end_time, PhaseTime::FromEnd(_)) || after_loop(*i))
+            .map(|(_, phase)| phase.end_time.to_samples(p).saturating_sub(note_off))
+            .fold(0, u64::max)
+    }
+
+    // The value of the envelope at the current sample, following the
+    // release once the phases have finished.
+    fn value_with_release(&mut self, p: &Params) -> f32 {
+        let release = match &self.release {
+            Some(release) if p[Param::Sample] >= self.release_at => release,
+            _ => return self.value(p),
+        };
+        let duration = release.duration * p[Param::Rate];
+        let frame = p[Param::Sample] - self.release_at;
+        if frame >= duration {
+            return release.end_val;
+        }
+        let delta = release.end_val - self.release_from;
+        release
+            .ease_fn
+            .apply(frame, self.release_from, delta, duration)
+    }
+
+    // The value of the envelope at the current sample. Before the first
+    // phase the envelope holds its start value, and after each phase it
+    // holds that phase's end value until the next one starts. Envelopes
+    // without phases have no value, which is NaN.
+    fn value(&mut self, p: &Params) -> f32 {
+        let time = self.time(p, p[Param::Sample] as u64);
+        // The last phase to have started is the one in effect
+        let current = self
+            .phases
+            .iter()
+            .rposition(|phase| phase.start_time.to_samples(p) <= time);
+        let phase = match current {
+            Some(i) => &mut self.phases[i],
+            None => {
+                return self
+                    .phases
+                    .first()
+                    .map_or(f32::NAN, |phase| phase.start_val)
+            }
+        };
+        let start_time = phase.start_time.to_samples(p);
+        let end_time = phase.end_time.to_samples(p);
+        // A phase with no length jumps straight to its end value
+        if time > end_time || end_time == start_time {
+            return phase.start_val + phase.delta_val;
+        }
+        let duration = (end_time - start_time) as f32;
+        let frame = (time - start_time) as f32;
+        match &mut phase.curve {
+            Some(curve) => {
+                let mut t = p.clone();
+                t[Param::X] = frame / duration;
+                let position = curve.get_sample(&t).unwrap_or(f32::NAN);
+                phase.start_val + phase.delta_val * position
+            }
+            None => phase
+                .ease_fn
+                .apply(frame, phase.start_val, phase.delta_val, duration),
+        }
     }
 }
 
+// Check that each phase ends after it starts, and that the next one starts
+// after it ends. Only times measured in the same way can be compared
+// before a note is played.
+fn check_order(phases: &[EnvPhase]) -> EnvelopeResult<()> {
+    for (i, phase) in phases.iter().enumerate() {
+        if phase.start_time.compare(phase.end_time) == Some(Ordering::Greater) {
+            return Err(EnvelopeError::Backwards(i));
+        }
+        if let Some(next) = phases.get(i + 1) {
+            if phase.end_time.compare(next.start_time) == Some(Ordering::Greater) {
+                return Err(EnvelopeError::Overlap(i, i + 1));
+            }
+        }
+    }
+    Ok(())
+}
+
 impl SampleGen for Envelope {
-    fn cache(&mut self, _p: &Params) {}
-
-    fn get_sample(&self, _p: &Params) -> Option<f32> {
-        None
-    }
-
-    fn get_mod_sample(&self, p: &Params) -> Option<f32> {
-        let mut sample: f32 = NAN;
-        let time = p["sample"] as u64;
-        for phase in &self.phases {
-            let start_time = (phase.start_time * (p["duration"] as f32)) as u64;
-            let end_time = (phase.end_time * (p["duration"] as f32)) as u64;
-            if time >= start_time && time <= end_time {
-                let duration = (end_time - start_time) as f32;
-                let frame = (time - start_time) as f32;
-                sample = match &*phase.ease_fn {
-                    "BackIn" => Back::ease_in(frame, phase.start_val, phase.delta_val, duration),
-                    "BackOut" => Back::ease_out(frame, phase.start_val, phase.delta_val, duration),
-                    "BackInOut" => {
-                        Back::ease_in_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "BounceIn" => {
-                        Bounce::ease_in(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "BounceOut" => {
-                        Bounce::ease_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "BounceInOut" => {
-                        Bounce::ease_in_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "CircIn" => Circ::ease_in(frame, phase.start_val, phase.delta_val, duration),
-                    "CircOut" => Circ::ease_out(frame, phase.start_val, phase.delta_val, duration),
-                    "CircInOut" => {
-                        Circ::ease_in_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "CubicIn" => Cubic::ease_in(frame, phase.start_val, phase.delta_val, duration),
-                    "CubicOut" => {
-                        Cubic::ease_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "CubicInOut" => {
-                        Cubic::ease_in_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "ElasticIn" => {
-                        Elastic::ease_in(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "ElasticOut" => {
-                        Elastic::ease_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "ElasticInOut" => {
-                        Elastic::ease_in_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "ExpoIn" => Expo::ease_in(frame, phase.start_val, phase.delta_val, duration),
-                    "ExpoOut" => Expo::ease_out(frame, phase.start_val, phase.delta_val, duration),
-                    "ExpoInOut" => {
-                        Expo::ease_in_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "Linear" => phase.start_val + phase.delta_val / (duration / frame),
-                    "LinearIn" => phase.start_val + phase.delta_val / (duration / frame),
-                    "LinearOut" => phase.start_val + phase.delta_val / (duration / frame),
-                    "LinearInOut" => phase.start_val + phase.delta_val / (duration / frame),
-                    "QuadIn" => Quad::ease_in(frame, phase.start_val, phase.delta_val, duration),
-                    "QuadOut" => Quad::ease_out(frame, phase.start_val, phase.delta_val, duration),
-                    "QuadInOut" => {
-                        Quad::ease_in_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "QuartIn" => Quart::ease_in(frame, phase.start_val, phase.delta_val, duration),
-                    "QuartOut" => {
-                        Quart::ease_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "QuartInOut" => {
-                        Quart::ease_in_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "QuintIn" => Quint::ease_in(frame, phase.start_val, phase.delta_val, duration),
-                    "QuintOut" => {
-                        Quint::ease_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "QuintInOut" => {
-                        Quint::ease_in_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    "SineIn" => Sine::ease_in(frame, phase.start_val, phase.delta_val, duration),
-                    "SineOut" => Sine::ease_out(frame, phase.start_val, phase.delta_val, duration),
-                    "SineInOut" => {
-                        Sine::ease_in_out(frame, phase.start_val, phase.delta_val, duration)
-                    }
-                    _ => unimplemented!(),
-                };
+    fn cache(&mut self, p: &Params) {
+        for phase in &mut self.phases {
+            if let Some(curve) = &mut phase.curve {
+                curve.cache(p);
             }
         }
+        if let Some(release) = self.release.clone() {
+            self.release_at = p[Param::Duration] + self.tail(p) as f32;
+            let mut end = p.clone();
+            end[Param::Sample] = self.release_at;
+            let value = self.value(&end);
+            // Without any phases, there is nothing to release from.
+            self.release_from = if value.is_nan() {
+                release.end_val
+            } else {
+                value
+            };
+        }
+    }
+
+    fn get_sample(&mut self, p: &Params) -> Option<f32> {
+        Some(self.value_with_release(p)).filter(|x| !x.is_nan())
+    }
+
+    fn get_mod_sample(&mut self, p: &Params) -> Option<f32> {
         // Invert the value so it can be multiplied by the modulator depth
-        match sample {
-            x if x == NAN => None,
-            x if x != NAN => Some(1.0 - x),
-            _ => unreachable!()
+        self.get_sample(p).map(|x| 1.0 - x)
+    }
+
+    // The release starts once any phases after the end of the note have
+    // finished.
+    fn release(&self, p: &Params) -> u64 {
+        let release = match &self.release {
+            Some(release) => (release.duration * p[Param::Rate]) as u64,
+            None => 0,
+        };
+        self.tail(p) + release
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::waveform::function::FunctionTable;
+
+    // A one second note at 100 samples a second and 120 beats a minute
+    fn params() -> Params {
+        let mut p = Params::new();
+        p[Param::Rate] = 100.0;
+        p[Param::Duration] = 100.0;
+        p[Param::Tempo] = 120.0;
+        p
+    }
+
+    fn phase(start_time: PhaseTime, end_time: PhaseTime, start_val: f32, end_val: f32) -> EnvPhase {
+        EnvPhase {
+            start_time,
+            end_time,
+            start_val,
+            delta_val: end_val - start_val,
+            ease_fn: Easing::Linear,
+            curve: None,
+        }
+    }
+
+    fn linear_release(duration: f32, end_val: f32) -> Option<Release> {
+        Some(Release {
+            duration,
+            end_val,
+            ease_fn: Easing::Linear,
+        })
+    }
+
+    // The envelope's value at each sample of `expected`, checked against
+    // it
+    fn assert_values(envelope: &mut Envelope, p: &Params, expected: &[(u64, f32)]) {
+        let mut p = p.clone();
+        envelope.cache(&p);
+        for &(sample, value) in expected {
+            p[Param::Sample] = sample as f32;
+            let actual = envelope.get_sample(&p).unwrap();
+            assert!(
+                (actual - value).abs() < 1e-5,
+                "sample {}: expected {}, got {}",
+                sample,
+                value,
+                actual
+            );
         }
     }
+
+    #[test]
+    fn seconds_do_not_depend_on_note_length() {
+        let phases = vec![phase(
+            PhaseTime::Seconds(0.0),
+            PhaseTime::Seconds(0.5),
+            0.0,
+            1.0,
+        )];
+        let mut envelope = Envelope::new(phases, None, None).unwrap();
+        let mut p = params();
+        assert_values(&mut envelope, &p, &[(25, 0.5), (50, 1.0)]);
+        p[Param::Duration] = 400.0;
+        assert_values(&mut envelope, &p, &[(25, 0.5), (50, 1.0)]);
+    }
+
+    #[test]
+    fn from_end_is_measured_from_note_off() {
+        let phases = vec![phase(
+            PhaseTime::FromEnd(-0.5),
+            PhaseTime::FromEnd(0.0),
+            1.0,
+            0.0,
+        )];
+        let mut envelope = Envelope::new(phases, None, None).unwrap();
+        let mut p = params();
+        assert_values(&mut envelope, &p, &[(25, 1.0), (75, 0.5), (100, 0.0)]);
+        p[Param::Duration] = 200.0;
+        assert_values(&mut envelope, &p, &[(75, 1.0), (175, 0.5)]);
+    }
+
+    #[test]
+    fn beats_follow_the_tempo() {
+        let phases = vec![phase(
+            PhaseTime::Beats(0.0),
+            PhaseTime::Beats(1.0),
+            0.0,
+            1.0,
+        )];
+        let mut envelope = Envelope::new(phases, None, None).unwrap();
+        let mut p = params();
+        assert_values(&mut envelope, &p, &[(25, 0.5), (50, 1.0)]);
+        p[Param::Tempo] = 60.0;
+        assert_values(&mut envelope, &p, &[(25, 0.25), (50, 0.5)]);
+    }
+
+    #[test]
+    fn adsr_holds_sustain_then_releases() {
+        let mut envelope = Envelope::adsr(0.1, 0.1, 0.5, linear_release(0.2, 0.0));
+        let p = params();
+        assert_values(
+            &mut envelope,
+            &p,
+            &[
+                (5, 0.5),
+                (10, 1.0),
+                (15, 0.75),
+                (90, 0.5),
+                (110, 0.25),
+                (130, 0.0),
+            ],
+        );
+        assert_eq!(envelope.release(&p), 20);
+    }
+
+    #[test]
+    fn release_waits_for_phases_after_note_off() {
+        let phases = vec![
+            phase(PhaseTime::Fraction(0.0), PhaseTime::Fraction(1.0), 1.0, 1.0),
+            phase(PhaseTime::FromEnd(0.0), PhaseTime::FromEnd(0.2), 1.0, 0.5),
+        ];
+        let mut envelope = Envelope::new(phases, linear_release(0.1, 0.0), None).unwrap();
+        let p = params();
+        assert_values(
+            &mut envelope,
+            &p,
+            &[(110, 0.75), (120, 0.5), (125, 0.25), (130, 0.0), (200, 0.0)],
+        );
+        assert_eq!(envelope.release(&p), 30);
+    }
+
+    fn curve(equation: &str) -> Option<Waveform> {
+        let t = [("t", Param::X)];
+        let context = String::from("curve");
+        Some(Waveform::from_equation(context, equation, &t, &FunctionTable::new()).unwrap())
+    }
+
+    #[test]
+    fn curves_scale_the_change_over_the_phase() {
+        let mut falling = phase(PhaseTime::Seconds(0.0), PhaseTime::Seconds(1.0), 1.0, 0.5);
+        falling.curve = curve("t^2");
+        let mut envelope = Envelope::new(vec![falling], None, None).unwrap();
+        assert_values(
+            &mut envelope,
+            &params(),
+            &[(0, 1.0), (50, 0.875), (100, 0.5)],
+        );
+    }
+
+    // A rise and fall over 0.2 seconds, followed by a rise over 0.1
+    // seconds
+    fn wobble() -> Vec<EnvPhase> {
+        vec![
+            phase(PhaseTime::Seconds(0.0), PhaseTime::Seconds(0.1), 0.0, 1.0),
+            phase(PhaseTime::Seconds(0.1), PhaseTime::Seconds(0.2), 1.0, 0.0),
+            phase(PhaseTime::Seconds(0.2), PhaseTime::Seconds(0.3), 0.0, 1.0),
+        ]
+    }
+
+    #[test]
+    fn counted_loops_continue_after_the_last_repetition() {
+        let looping = Some(Loop {
+            start: 0,
+            end: 1,
+            count: Some(2),
+        });
+        let mut envelope = Envelope::new(wobble(), None, looping).unwrap();
+        assert_values(
+            &mut envelope,
+            &params(),
+            &[
+                (5, 0.5),
+                (19, 0.1),
+                (20, 0.0),
+                (25, 0.5),
+                (45, 0.5),
+                (60, 1.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn uncounted_loops_repeat_until_note_off() {
+        let looping = Some(Loop {
+            start: 0,
+            end: 1,
+            count: None,
+        });
+        let mut envelope = Envelope::new(wobble(), linear_release(0.1, 0.0), looping).unwrap();
+        let p = params();
+        // The repetition in progress at note-off finishes before the
+        // phases after the loop and then the release
+        assert_values(
+            &mut envelope,
+            &p,
+            &[(90, 1.0), (105, 0.5), (125, 0.5), (130, 1.0), (135, 0.5)],
+        );
+        assert_eq!(envelope.release(&p), 40);
+    }
+
+    #[test]
+    fn loops_must_fit_the_phases() {
+        let looping = |start, end, count| Some(Loop { start, end, count });
+        assert_eq!(
+            Envelope::new(wobble(), None, looping(1, 3, None)).err(),
+            Some(EnvelopeError::LoopRange(1, 3, 3))
+        );
+        assert_eq!(
+            Envelope::new(wobble(), None, looping(2, 1, None)).err(),
+            Some(EnvelopeError::LoopRange(2, 1, 3))
+        );
+        assert_eq!(
+            Envelope::new(wobble(), None, looping(0, 1, Some(0))).err(),
+            Some(EnvelopeError::LoopCount)
+        );
+    }
+
+    #[test]
+    fn envelopes_hold_before_between_and_after_phases() {
+        let phases = vec![
+            phase(PhaseTime::Seconds(0.1), PhaseTime::Seconds(0.2), 0.2, 1.0),
+            phase(PhaseTime::Seconds(0.4), PhaseTime::Seconds(0.5), 0.5, 0.0),
+        ];
+        let mut envelope = Envelope::new(phases, None, None).unwrap();
+        assert_values(
+            &mut envelope,
+            &params(),
+            &[(0, 0.2), (15, 0.6), (30, 1.0), (45, 0.25), (90, 0.0)],
+        );
+    }
+
+    #[test]
+    fn zero_length_phases_jump_to_their_end_value() {
+        let phases = vec![
+            phase(PhaseTime::Seconds(0.0), PhaseTime::Seconds(0.1), 0.0, 0.5),
+            phase(PhaseTime::Seconds(0.1), PhaseTime::Seconds(0.1), 0.5, 1.0),
+            phase(PhaseTime::Seconds(0.2), PhaseTime::Seconds(0.3), 1.0, 0.0),
+        ];
+        let mut envelope = Envelope::new(phases, None, None).unwrap();
+        assert_values(&mut envelope, &params(), &[(5, 0.25), (10, 1.0), (15, 1.0)]);
+    }
+
+    #[test]
+    fn envelopes_without_phases_have_no_value() {
+        let mut envelope = Envelope::new(Vec::new(), None, None).unwrap();
+        let p = params();
+        envelope.cache(&p);
+        assert_eq!(envelope.get_sample(&p), None);
+        assert_eq!(envelope.get_mod_sample(&p), None);
+    }
+
+    #[test]
+    fn phases_must_be_in_order() {
+        let backwards = vec![phase(
+            PhaseTime::Seconds(0.2),
+            PhaseTime::Seconds(0.1),
+            0.0,
+            1.0,
+        )];
+        assert_eq!(
+            Envelope::new(backwards, None, None).err(),
+            Some(EnvelopeError::Backwards(0))
+        );
+        let overlapping = vec![
+            phase(PhaseTime::Seconds(0.0), PhaseTime::Seconds(0.2), 0.0, 1.0),
+            phase(PhaseTime::Seconds(0.1), PhaseTime::Seconds(0.3), 1.0, 0.0),
+        ];
+        assert_eq!(
+            Envelope::new(overlapping, None, None).err(),
+            Some(EnvelopeError::Overlap(0, 1))
+        );
+        // Times measured in different ways can only be compared once a
+        // note is played
+        let mixed = vec![
+            phase(PhaseTime::Seconds(0.0), PhaseTime::Fraction(0.5), 0.0, 1.0),
+            phase(PhaseTime::Seconds(0.1), PhaseTime::FromEnd(0.0), 1.0, 0.0),
+        ];
+        assert!(Envelope::new(mixed, None, None).is_ok());
+    }
 }
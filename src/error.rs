@@ -19,6 +19,11 @@ NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FO
 DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE. */
 
+// `failure_derive` expands to impls nested inside anonymous constants.
+#![allow(non_local_definitions)]
+
+use std::fmt;
+
 /// Parser result type
 ///
 /// Returned from parsing functions when success can't be guaranteed.
@@ -54,21 +59,240 @@ pub type ParseResult<T> = ::std::result::Result<T, ParseError>;
 /// Parser error type
 ///
 /// This distinguishes between the different
-/// kinds of errors that the `Parser` can encounter.
-///
-/// TODO: Both variants of this type should have more data
-/// attached. It would be nice to know _what_ token was unexpected or
-/// what the incomplete expression could have continued with (for
-/// error recovery). It probably makes sense to roll this in with
-/// adding position information to the parser tokens and errors
-/// though.
+/// kinds of errors that the `Parser` can encounter. Each carries the
+/// byte offset into the equation at which the problem was found.
 #[derive(Fail, Debug, PartialEq)]
 pub enum ParseError {
     /// Unexpected token.
-    #[fail(display = "unexpected token")]
-    Unexpected,
+    #[fail(display = "expected {} but found {}", expected, found)]
+    Unexpected {
+        position: usize,
+        expected: String,
+        found: String,
+    },
 
     /// Incomplete data
-    #[fail(display = "incomplete expression")]
-    Incomplete,
+    #[fail(display = "expected {} but found end of input", expected)]
+    Incomplete { position: usize, expected: String },
+
+    /// A function was called with the wrong number of arguments.
+    #[fail(
+        display = "function '{}' expects {} arguments but was given {}",
+        name, arity, given
+    )]
+    Arity {
+        position: usize,
+        name: String,
+        arity: usize,
+        given: usize,
+    },
+
+    /// A built-in constant was bound to a new value.
+    #[fail(display = "'{}' is a built-in constant", name)]
+    Constant { position: usize, name: String },
+}
+
+impl ParseError {
+    /// Byte offset into the equation at which the error was found.
+    pub fn position(&self) -> usize {
+        match *self {
+            ParseError::Unexpected { position, .. }
+            | ParseError::Incomplete { position, .. }
+            | ParseError::Arity { position, .. }
+            | ParseError::Constant { position, .. } => position,
+        }
+    }
+}
+
+/// Function result type
+///
+/// Returned when defining or checking calls to user-defined functions.
+pub type FunctionResult<T> = ::std::result::Result<T, FunctionError>;
+
+/// Function error type
+///
+/// Describes a problem with the user-defined functions of an
+/// arrangement, or with a call to one of them.
+#[derive(Fail, Debug, PartialEq)]
+pub enum FunctionError {
+    /// Call to a function which is neither built in nor user-defined.
+    #[fail(display = "unknown function '{}'", _0)]
+    Unknown(String),
+
+    /// Function called with the wrong number of arguments.
+    #[fail(display = "function '{}' expects {} arguments but was given {}", _0, _1, _2)]
+    Arity(String, usize, usize),
+
+    /// Function or waveform defined more than once, or with the name of a
+    /// built-in function, constant or renderer input.
+    #[fail(display = "the name '{}' is already defined", _0)]
+    Duplicate(String),
+
+    /// Function with a parameter named after a built-in constant.
+    #[fail(display = "'{}' is a built-in constant", _0)]
+    Constant(String),
+
+    /// Function or waveform which refers to itself, directly or through
+    /// other functions and waveforms.
+    #[fail(display = "recursive definition: {}", _0)]
+    Recursive(String),
+
+    /// Call which records a value every sample, made from a branch of a
+    /// conditional which isn't evaluated every sample.
+    #[fail(
+        display = "'{}' records every sample, so can't be called from a conditional branch",
+        _0
+    )]
+    Conditional(String),
+
+    /// Function body which uses names that aren't parameters or inputs.
+    #[fail(display = "{}", _0)]
+    Names(NameError),
+}
+
+/// Equation result type
+///
+/// Returned when loading the equations of an arrangement.
+pub type EquationResult<T> = ::std::result::Result<T, EquationError>;
+
+/// Equation error type
+///
+/// Wraps a problem with an equation together with what the equation
+/// belongs to (e.g. "waveform 'square'") and, where known, the
+/// equation's source and the position of the problem within it.
+#[derive(Debug, PartialEq)]
+pub struct EquationError {
+    context: String,
+    message: String,
+    location: Option<(String, usize)>,
+}
+
+impl EquationError {
+    /// An error which isn't tied to a particular position in the equation.
+    pub fn new<D: fmt::Display>(context: String, error: D) -> EquationError {
+        EquationError {
+            context,
+            message: error.to_string(),
+            location: None,
+        }
+    }
+
+    /// An error from parsing `equation`, pointing at where it was found.
+    pub fn parse(context: String, equation: &str, error: &ParseError) -> EquationError {
+        EquationError {
+            context,
+            message: error.to_string(),
+            location: Some((String::from(equation), error.position())),
+        }
+    }
+}
+
+impl fmt::Display for EquationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (equation, position) = match self.location {
+            Some((ref equation, position)) => (equation, position),
+            None => return write!(f, "{}: {}", self.context, self.message),
+        };
+        // Report the line and column the position falls on, counting
+        // both from one, and underline it with a caret.
+        let before = &equation[..position];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = equation[position..]
+            .find('\n')
+            .map_or(equation.len(), |i| position + i);
+        let column = before[line_start..].chars().count() + 1;
+        write!(f, "{}, ", self.context)?;
+        if line_start > 0 || line_end < equation.len() {
+            write!(f, "line {}, ", before.matches('\n').count() + 1)?;
+        }
+        write!(
+            f,
+            "column {}: {}\n    {}\n    {}^",
+            column,
+            self.message,
+            &equation[line_start..line_end],
+            " ".repeat(column - 1)
+        )
+    }
+}
+
+impl failure::Fail for EquationError {}
+
+/// Name result type
+///
+/// Returned when checking the identifiers used by an equation.
+pub type NameResult<T> = ::std::result::Result<T, NameError>;
+
+/// Name error type
+///
+/// Lists every identifier in an equation which is neither an input
+/// supplied by the renderer nor bound by `let` or a function parameter,
+/// along with the closest known name, if any is close enough.
+#[derive(Debug, PartialEq)]
+pub struct NameError {
+    pub unknown: Vec<(String, Option<String>)>,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let plural = if self.unknown.len() == 1 { "" } else { "s" };
+        write!(f, "unknown name{}", plural)?;
+        for (i, (name, suggestion)) in self.unknown.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{}'{}'", separator, name)?;
+            if let Some(suggestion) = suggestion {
+                write!(f, " (did you mean '{}'?)", suggestion)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl failure::Fail for NameError {}
+
+/// Easing error type
+///
+/// Returned when the easing function of an envelope phase can't be
+/// understood.
+#[derive(Fail, Debug, PartialEq)]
+pub enum EasingError {
+    /// Name which isn't one of the easing functions, along with the
+    /// names which are.
+    #[fail(display = "unknown easing function '{}', expected one of {}", _0, _1)]
+    Unknown(String, String),
+
+    /// Easing function with a missing or out of range argument.
+    #[fail(display = "invalid argument in easing function '{}'", _0)]
+    Argument(String),
+}
+
+/// Envelope result type
+///
+/// Returned when creating an envelope from an arrangement.
+pub type EnvelopeResult<T> = ::std::result::Result<T, EnvelopeError>;
+
+/// Envelope error type
+///
+/// Returned when the phases of an envelope don't fit together.
+#[derive(Fail, Debug, PartialEq)]
+pub enum EnvelopeError {
+    /// Loop over phases which don't exist, or which ends before it
+    /// starts.
+    #[fail(
+        display = "loop from phase {} to phase {} doesn't fit in the envelope's {} phases",
+        _0, _1, _2
+    )]
+    LoopRange(usize, usize, usize),
+
+    /// Loop which never plays.
+    #[fail(display = "loop count must be at least 1")]
+    LoopCount,
+
+    /// Phase which ends before it starts.
+    #[fail(display = "phase {} ends before it starts", _0)]
+    Backwards(usize),
+
+    /// Phase which starts before the previous phase ends.
+    #[fail(display = "phase {} ends after phase {} starts", _0, _1)]
+    Overlap(usize, usize),
 }
@@ -1,38 +1,163 @@
 //use crate::envelope::Envelope;
-use crate::samplegen::{Params, SampleGen};
+use crate::samplegen::{Param, Params, SampleGen};
 use crate::waveform::Waveform;
 
 pub struct Modulator {
-    pub modulator: Box<SampleGen>,
+    pub modulator: Box<dyn SampleGen>,
     pub depth: f32
 }
 
+pub struct PitchModulator {
+    pub modulator: Box<dyn SampleGen>,
+    // Semitones to bend the pitch by for each unit of the modulator's
+    // value, or None if the value is the ratio to multiply the frequency by
+    pub semitones: Option<f32>,
+}
+
+impl PitchModulator {
+    // The ratio this modulator multiplies the frequency by
+    fn ratio(&mut self, p: &Params) -> f32 {
+        match (self.modulator.get_sample(p), self.semitones) {
+            (Some(m), Some(semitones)) => 2f32.powf(m * semitones / 12.0),
+            (Some(m), None) => m,
+            (None, _) => 1.0,
+        }
+    }
+}
+
+/// How the level of a note depends on how hard it was played.
+pub enum VelocityCurve {
+    /// Every note is played at full level.
+    Fixed,
+    /// The level rises in proportion to the velocity.
+    Linear,
+    /// The level in decibels rises in proportion to the velocity.
+    Exponential,
+    /// The mirror image of `Exponential`, which rises quickly for soft
+    /// notes and levels off for loud ones.
+    Logarithmic,
+    /// An equation over `velocity` and the other per-note inputs, giving
+    /// the level from 0 (the quietest) to 1 (full level).
+    Expression(Box<Waveform>),
+}
+
+impl VelocityCurve {
+    pub fn from_name(name: &str) -> Option<VelocityCurve> {
+        match name {
+            "fixed" => Some(VelocityCurve::Fixed),
+            "linear" => Some(VelocityCurve::Linear),
+            "exponential" => Some(VelocityCurve::Exponential),
+            "logarithmic" => Some(VelocityCurve::Logarithmic),
+            _ => None,
+        }
+    }
+
+    /// The gain for the note being played, where a velocity of 0 is
+    /// `range` decibels quieter than a velocity of 1.
+    fn gain(&mut self, range: f32, p: &Params) -> f32 {
+        let v = p[Param::Velocity];
+        let floor = 10f32.powf(-range / 20.0);
+        match self {
+            VelocityCurve::Fixed => 1.0,
+            VelocityCurve::Linear => floor + (1.0 - floor) * v,
+            VelocityCurve::Exponential => floor.powf(1.0 - v),
+            VelocityCurve::Logarithmic => 1.0 + floor - floor.powf(v),
+            VelocityCurve::Expression(equation) => {
+                equation.cache(p);
+                let level = equation.get_sample(p).unwrap_or(1.0).clamp(0.0, 1.0);
+                floor + (1.0 - floor) * level
+            }
+        }
+    }
+}
+
 pub struct Instrument {
     pub name: String,
     pub midi_inst: u8,
     pub midi_percussion: bool,
-    pub carrier: Box<SampleGen>,
+    pub carrier: Box<dyn SampleGen>,
     pub am: Vec<Modulator>,
-    //pub fm: Vec<Modulator>
+    pub fm: Vec<Modulator>,
+    pub pitch: Vec<PitchModulator>,
+    // Phase of the current note, accumulated sample by sample so that
+    // the fm and pitch modulators can vary the frequency without
+    // discontinuities. The carrier's `x` follows it.
+    pub phase: f64,
+    // How far the carrier has got through the current note, in samples,
+    // which advances faster or slower as the pitch modulators bend it.
+    // Sample banks play from this position.
+    pub position: f64,
+    pub velocity_curve: VelocityCurve,
+    // Dynamic range of the velocity curve, in decibels
+    pub velocity_range: f32,
+    // Gain of the current note, from the velocity curve
+    pub gain: f32,
 }
 
 impl SampleGen for Instrument {
     fn cache(&mut self, p: &Params) {
-        self.carrier.cache(&p);
+        self.phase = 0.0;
+        self.position = 0.0;
+        self.gain = self.velocity_curve.gain(self.velocity_range, p);
+        self.carrier.cache(p);
+        for modulator in self.am.iter_mut().chain(&mut self.fm) {
+            modulator.modulator.cache(p);
+        }
+        for modulator in &mut self.pitch {
+            modulator.modulator.cache(p);
+        }
     }
 
-    fn get_sample(&self, p: &Params) -> Option<f32> {
-        let mut c = self.carrier.get_sample(&p).unwrap();
-        for modulator in &self.am {
-            match modulator.modulator.get_mod_sample(&p) {
-                Some(m) => c *= 1.0 - m * modulator.depth,
-                None => ()
+    fn get_sample(&mut self, p: &Params) -> Option<f32> {
+        let mut p = p.clone();
+        p[Param::Phase] = self.phase as f32;
+
+        // Each fm modulator scales the frequency the phase advances at
+        let mut freq = p[Param::Frequency];
+        for modulator in &mut self.fm {
+            if let Some(m) = modulator.modulator.get_sample(&p) {
+                freq *= 1.0 + m * modulator.depth;
+            }
+        }
+        // Each pitch modulator bends both the frequency and how fast the
+        // carrier moves through the note
+        let mut ratio = 1.0;
+        for modulator in &mut self.pitch {
+            ratio *= modulator.ratio(&p);
+        }
+        freq *= ratio;
+
+        let mut carrier = p.clone();
+        // Without any modulators x is left as the renderer computed it
+        // from the sample, rather than accumulating rounding errors
+        if !self.fm.is_empty() || !self.pitch.is_empty() {
+            carrier[Param::X] = self.phase as f32;
+        }
+        carrier[Param::CarrierFrequency] = freq;
+        carrier[Param::Sample] = self.position as f32;
+        self.phase += f64::from(freq / p[Param::Rate]);
+        self.position += f64::from(ratio);
+
+        let mut c = self.carrier.get_sample(&carrier).unwrap() * self.gain;
+        for modulator in &mut self.am {
+            if let Some(m) = modulator.modulator.get_mod_sample(&p) {
+                c *= 1.0 - m * modulator.depth;
             }
         }
         Some(c)
     }
 
-    fn get_mod_sample(&self, _p: &Params) -> Option<f32> {
+    fn get_mod_sample(&mut self, _p: &Params) -> Option<f32> {
         None
     }
+
+    // The note rings on for as long as any part of the instrument does.
+    fn release(&self, p: &Params) -> u64 {
+        self.am
+            .iter()
+            .chain(&self.fm)
+            .map(|m| m.modulator.release(p))
+            .chain(self.pitch.iter().map(|m| m.modulator.release(p)))
+            .fold(self.carrier.release(p), u64::max)
+    }
 }
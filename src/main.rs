@@ -14,29 +14,36 @@ use std::fs;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
+use std::process;
+
+pub mod easing;
 
 pub mod envelope;
-use envelope::{EnvPhase, Envelope};
+use envelope::{EnvPhase, Envelope, Loop, PhaseTime, Release};
 
 pub mod instrument;
-use instrument::{Modulator, Instrument};
+use instrument::{Modulator, Instrument, PitchModulator, VelocityCurve};
 
 pub mod midi;
 use midi::{MidiHandler, Note};
 
 pub mod parse;
-use parse::{JSONArrangement};
+use parse::{JSONArrangement, JSONModulator, JSONPhaseTime, JSONPitchModulator, JSONTiming};
 
 pub mod sample_bank;
 use sample_bank::SampleBank;
 
 pub mod samplegen;
-use samplegen::{Params, SampleGen};
+use samplegen::{Param, Params, SampleGen};
 
 pub mod waveform;
-use waveform::Waveform;
+use waveform::function::FunctionTable;
+use waveform::parse::parse_single;
+use waveform::noise::hash;
+use waveform::{define_waveforms, Waveform};
 
 pub mod error;
+use error::EquationError;
 
 fn read_midi_file(h: &mut MidiHandler, p: &Path) {
     // This function does no error handling as it causes lifetime problems.
@@ -53,11 +60,122 @@ fn parse_midi_file(f: String) -> Result<Vec<Note>, Error> {
     let _ = fs::metadata(p)?;
 
     let mut handler = MidiHandler::new();
-    read_midi_file(&mut handler, &p);
+    read_midi_file(&mut handler, p);
     Ok(handler.finished_notes.clone())
 }
 
-fn main() -> Result<(), Error> {
+fn phase_time(timing: JSONTiming, t: JSONPhaseTime) -> PhaseTime {
+    match (timing, t) {
+        (JSONTiming::NoteOff, JSONPhaseTime::Number(s)) => PhaseTime::FromEnd(s),
+        (JSONTiming::NoteOff, JSONPhaseTime::Seconds(s)) => PhaseTime::FromEnd(s),
+        (JSONTiming::Absolute, JSONPhaseTime::Number(s)) => PhaseTime::Seconds(s),
+        (JSONTiming::Proportional, JSONPhaseTime::Number(f)) => PhaseTime::Fraction(f),
+        (JSONTiming::Beats, JSONPhaseTime::Number(b)) => PhaseTime::Beats(b),
+        // Times with a unit are always from the start of the note
+        (_, JSONPhaseTime::Seconds(s)) => PhaseTime::Seconds(s),
+    }
+}
+
+// Figure out which instrument plays the note
+fn find_instrument(instruments: &HashMap<String, Instrument>, n: &Note) -> String {
+    let mut maybe_inst = None;
+    for i in instruments.values() {
+        if !i.midi_percussion && n.program == i.midi_inst {
+            maybe_inst = Some(i.name.clone());
+        }
+    }
+    if maybe_inst.is_none() {
+        let error = format!("Could not find an instrument mapped to MIDI patch {:?}", n.program);
+        panic!("{}", error)
+    }
+    maybe_inst.unwrap()
+}
+
+// The parameters which stay the same for every sample of the note
+fn note_params(n: &Note, seed: u64) -> Params {
+    let mut p = Params::new();
+    p[Param::Duration] = (n.end_time - n.start_time) as f32;
+    p[Param::Rate] = 44100.0;
+    p[Param::MidiNote] = n.midi_note as f32;
+    p[Param::Frequency] = n.freq;
+    p[Param::CarrierFrequency] = n.freq;
+    p[Param::Velocity] = f32::from(n.velocity) / 127.0;
+    p[Param::ReleaseVelocity] = f32::from(n.release_velocity) / 127.0;
+    p[Param::Channel] = f32::from(n.channel);
+    p[Param::Program] = f32::from(n.program);
+    p[Param::NoteStart] = n.start_time as f32 / 44100.0;
+    p[Param::NoteBeat] = n.start_beat;
+    p[Param::Tempo] = 60.0 * 44100.0 / n.tempo as f32;
+    p.seed = hash(hash(seed, n.start_time), n.midi_note as u64);
+    p
+}
+
+// TODO: allow using instruments as modulators
+fn find_modulator(
+    name: &str,
+    waveforms: &HashMap<String, Waveform>,
+    envelopes: &HashMap<String, Envelope>,
+) -> Option<Box<dyn SampleGen>> {
+    if waveforms.contains_key(name) {
+        Some(Box::new(waveforms[name].clone()))
+    } else if envelopes.contains_key(name) {
+        Some(Box::new(envelopes[name].clone()))
+    } else {
+        None
+    }
+}
+
+fn make_modulators(
+    list: Vec<JSONModulator>,
+    waveforms: &HashMap<String, Waveform>,
+    envelopes: &HashMap<String, Envelope>,
+) -> Vec<Modulator> {
+    let mut modulators = Vec::new();
+    for m in list {
+        if let Some(modulator) = find_modulator(&m.modulator, waveforms, envelopes) {
+            modulators.push(Modulator {
+                modulator,
+                depth: m.depth
+            });
+        }
+    }
+    modulators
+}
+
+// Unlike amplitude and frequency modulators, a pitch modulator which
+// doesn't name a waveform or envelope is an error.
+fn make_pitch_modulators(
+    instrument: &str,
+    list: Vec<JSONPitchModulator>,
+    waveforms: &HashMap<String, Waveform>,
+    envelopes: &HashMap<String, Envelope>,
+) -> Result<Vec<PitchModulator>, EquationError> {
+    let mut modulators = Vec::new();
+    for m in list {
+        let modulator = match find_modulator(&m.modulator, waveforms, envelopes) {
+            Some(modulator) => modulator,
+            None => {
+                let context = format!("pitch modulator of instrument '{}'", instrument);
+                let message = format!("no waveform or envelope named '{}'", m.modulator);
+                return Err(EquationError::new(context, message));
+            }
+        };
+        modulators.push(PitchModulator {
+            modulator,
+            semitones: if m.ratio { None } else { Some(m.depth) }
+        });
+    }
+    Ok(modulators)
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Error> {
     let args: Vec<String> = env::args().collect();
     let f = File::open(&args[1])?;
     let reader = BufReader::new(f);
@@ -71,32 +189,74 @@ fn main() -> Result<(), Error> {
     let mut sample_banks = HashMap::new();
     for b in json.sample_banks {
         let name = b.name.to_string();
-        sample_banks.insert(name, SampleBank::new(b.name.to_string(), b.files));
+        sample_banks.insert(name, SampleBank::new(b.name.to_string(), b.files, b.play_to_end));
     }
 
-    // Create waveforms based on the JSON parameters
+    // Create user-defined functions based on the JSON parameters
+    let mut functions = FunctionTable::new();
+    for f in json.functions {
+        let context = format!("function '{}'", f.name);
+        let body = parse_single(&f.body)
+            .map_err(|e| EquationError::parse(context.clone(), &f.body, &e))?;
+        functions.define(f.name, f.params, body)
+            .map_err(|e| EquationError::new(context, e))?;
+    }
+
+    // Create waveforms based on the JSON parameters. Waveforms can refer
+    // to each other, so all of them are defined before any are compiled.
+    let equations: Vec<(&str, &str)> = json.waveforms.iter()
+        .map(|w| (w.name.as_str(), w.equation.as_str()))
+        .collect();
+    define_waveforms(&equations, &mut functions)?;
+    functions.check()?;
     let mut waveforms = HashMap::new();
-    for w in json.waveforms {
-        let name = w.name.to_string();
-        waveforms.insert(name, Waveform::new(w.equation.to_string()));
+    for w in &json.waveforms {
+        waveforms.insert(w.name.to_string(), Waveform::new(&w.name, &functions));
     }
 
     //Create envelopes based on the JSON parameters
     let mut envelopes = HashMap::new();
     for e in json.envelopes {
         let name = e.name.to_string();
-        let mut phases = Vec::new();
-        for p in e.phases {
-            let efn = p.ease_fn.to_owned();
-            phases.push(EnvPhase {
-                start_time: p.start_time,
-                end_time: p.end_time,
+        let release = e.release.map(|r| Release {
+            duration: r.duration,
+            end_val: r.end_val,
+            ease_fn: r.ease_fn
+        });
+        let phases = match e.phases {
+            Some(phases) => phases,
+            None => {
+                envelopes.insert(name, Envelope::adsr(e.attack, e.decay, e.sustain, release));
+                continue;
+            }
+        };
+        let mut envelope_phases = Vec::new();
+        for p in phases {
+            let curve = match p.curve {
+                Some(curve) => {
+                    let context = format!("curve of envelope '{}'", e.name);
+                    let t = [("t", Param::X)];
+                    Some(Waveform::from_equation(context, &curve, &t, &functions)?)
+                }
+                None => None
+            };
+            envelope_phases.push(EnvPhase {
+                start_time: phase_time(p.timing, p.start_time),
+                end_time: phase_time(p.timing, p.end_time),
                 start_val: p.start_val,
                 delta_val: p.end_val,
-                ease_fn: efn
+                ease_fn: p.ease_fn,
+                curve
             });
         }
-        envelopes.insert(name, Envelope::new(phases));
+        let looping = e.looping.map(|l| Loop {
+            start: l.start,
+            end: l.end,
+            count: l.count
+        });
+        let envelope = Envelope::new(envelope_phases, release, looping)
+            .map_err(|err| EquationError::new(format!("envelope '{}'", name), err))?;
+        envelopes.insert(name, envelope);
     }
 
     //Create instruments based on the JSON parameters
@@ -104,22 +264,16 @@ fn main() -> Result<(), Error> {
     for i in json.instruments {
         let name = i.name.to_string();
 
-        let mut am = Vec::new();
-        for m in i.am {
-            // TODO: allow using instruments as modulators
-            let mod_name = m.modulator.to_string();
-            if waveforms.contains_key(&mod_name) {
-                am.push(Modulator {
-                    modulator: Box::new(waveforms[&mod_name].clone()),
-                    depth: m.depth
-                });
-            } else if envelopes.contains_key(&mod_name) {
-                am.push(Modulator {
-                    modulator: Box::new(envelopes[&mod_name].clone()),
-                    depth: m.depth
-                });
+        let am = make_modulators(i.am, &waveforms, &envelopes);
+        let fm = make_modulators(i.fm, &waveforms, &envelopes);
+        let pitch = make_pitch_modulators(&i.name, i.pitch, &waveforms, &envelopes)?;
+        let velocity_curve = match VelocityCurve::from_name(&i.velocity_curve) {
+            Some(curve) => curve,
+            None => {
+                let context = format!("velocity curve of instrument '{}'", i.name);
+                VelocityCurve::Expression(Box::new(Waveform::from_equation(context, &i.velocity_curve, &[], &functions)?))
             }
-        }
+        };
         // What type is the carrier?
         if sample_banks.contains_key(&i.carrier) {
             instruments.insert(name.clone(), Instrument {
@@ -127,7 +281,14 @@ fn main() -> Result<(), Error> {
                 midi_inst: i.midi_inst,
                 midi_percussion: i.midi_percussion,
                 carrier: Box::new(sample_banks[&i.carrier].clone()),
-                am
+                am,
+                fm,
+                pitch,
+                phase: 0.0,
+                position: 0.0,
+                velocity_curve,
+                velocity_range: i.velocity_range,
+                gain: 1.0
             });
         } else if waveforms.contains_key(&i.carrier) {
             instruments.insert(name.clone(), Instrument {
@@ -135,7 +296,14 @@ fn main() -> Result<(), Error> {
                 midi_inst: i.midi_inst,
                 midi_percussion: i.midi_percussion,
                 carrier: Box::new(waveforms[&i.carrier].clone()),
-                am
+                am,
+                fm,
+                pitch,
+                phase: 0.0,
+                position: 0.0,
+                velocity_curve,
+                velocity_range: i.velocity_range,
+                gain: 1.0
             });
         } else {
             unimplemented!()
@@ -146,18 +314,24 @@ fn main() -> Result<(), Error> {
     let mut output_channels: HashMap<u8, Vec<f32>> = HashMap::new();
     for o in &json.outputs {
         for c in &o.channels {
-            if !output_channels.contains_key(&c) {
+            if !output_channels.contains_key(c) {
                 output_channels.insert(*c, Vec::new());
             }
         }
     }
 
-    // Find the end time of the final note
+    // Find the end time of the final note, including its release
     // TODO: Trim silence from the beginning and end of every output
     let mut final_note: usize = 0;
     for n in &notes {
-        if output_channels.contains_key(&n.channel) && n.end_time > (final_note as u64) {
-            final_note = n.end_time as usize;
+        if output_channels.contains_key(&n.channel) {
+            let p = note_params(n, json.seed);
+            let inst = instruments.get_mut(&find_instrument(&instruments, n)).unwrap();
+            inst.cache(&p);
+            let end_time = n.end_time + inst.release(&p);
+            if end_time > (final_note as u64) {
+                final_note = end_time as usize;
+            }
         }
     }
 
@@ -167,58 +341,42 @@ fn main() -> Result<(), Error> {
         let mut loudest = 0.0;
         for n in &notes {
             if o.channels.contains(&n.channel) {
-                let dur = n.end_time - n.start_time;
                 let begin = n.start_time;
+                let mut p = note_params(n, json.seed);
+                let inst = instruments.get_mut(&find_instrument(&instruments, n)).unwrap();
+                inst.cache(&p);
 
-                // Figure out which instrument to use
-                let mut maybe_inst = None;
-                for i in instruments.values() {
-                    if !i.midi_percussion && n.program == i.midi_inst {
-                        maybe_inst = Some(i.name.clone());
-                    }
-                }
-                if maybe_inst == None {
-                    let error = format!("Could not find an instrument mapped to MIDI patch {:?}", n.program);
-                    panic!(error)
-                }
-                let inst = maybe_inst.unwrap();
-
-                let mut cache_p: Params = HashMap::new();
-                cache_p.insert("midi_note".to_string(), n.midi_note as f32);
-                instruments.get_mut(&inst).unwrap().cache(&cache_p);
-
+                // Keep rendering after the end of the note until the
+                // instrument has been released
+                let dur = n.end_time - n.start_time + inst.release(&p);
                 for s in 0..dur {
-                    let mut p: Params = HashMap::new();
-                    p.insert("duration".to_string(), dur as f32);
-                    p.insert("sample".to_string(), s as f32);
-                    p.insert("time".to_string(), s as f32 / 44100.0);
-                    p.insert("rate".to_string(), 44100.0);
-                    p.insert("midi_note".to_string(), n.midi_note as f32);
-                    p.insert("x".to_string(), s as f32 * n.freq as f32 / 44100.0);
-                    // instruments.get_mut(&inst).unwrap().cache(&p);
-                    let o = instruments[&inst].get_sample(&p).unwrap();
+                    p[Param::Sample] = s as f32;
+                    p[Param::Time] = s as f32 / 44100.0;
+                    p[Param::SongTime] = (begin + s) as f32 / 44100.0;
+                    p[Param::X] = s as f32 * n.freq / 44100.0;
+                    let o = inst.get_sample(&p).unwrap();
                     output[(begin + s) as usize] += o;
                     if output[(begin + s) as usize] > loudest {
                         loudest = output[(begin + s) as usize];
                     }
                 }
             }
+        }
 
-            // Prepare to write the output as notes
-            let int_max = i16::max_value() as f32;
-            let spec = hound::WavSpec {
-                channels: 1,
-                sample_rate: 44100,
-                bits_per_sample: 16,
-                sample_format: hound::SampleFormat::Int,
-            };
-            let filename = o.output_file.clone();
-            let mut writer = hound::WavWriter::create(filename, spec).unwrap();
-
-            for t in 0..final_note {
-                let sample = ((output[t] / loudest) * int_max) as i16;
-                writer.write_sample(sample).unwrap();
-            }
+        // Prepare to write the output as notes
+        let int_max = i16::MAX as f32;
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let filename = o.output_file.clone();
+        let mut writer = hound::WavWriter::create(filename, spec).unwrap();
+
+        for s in output.iter().take(final_note) {
+            let sample = ((s / loudest) * int_max) as i16;
+            writer.write_sample(sample).unwrap();
         }
     }
 
@@ -9,6 +9,8 @@ struct PlayingNote {
     note: u8,
     velocity: u8,
     start_time: u64,
+    start_beat: f32,
+    tempo: u64,
 }
 
 #[derive(Clone)]
@@ -17,8 +19,14 @@ pub struct Note {
     pub program: u8,
     pub start_time: u64,
     pub end_time: u64,
+    // Position of the note in the song, in quarter notes
+    pub start_beat: f32,
+    // Samples per quarter note when the note started
+    pub tempo: u64,
     pub midi_note: u8,
     pub freq: f32,
+    pub velocity: u8,
+    pub release_velocity: u8,
     // pub frequencies: Envelope,
     // pub amplitudes: Envelope,
 }
@@ -75,11 +83,13 @@ impl Handler for MidiHandler {
                     note: *note,
                     velocity: *velocity,
                     start_time: u64::from(self.cur_time) / (self.time_base as u64) * self.tempo,
+                    start_beat: self.cur_time as f32 / self.time_base as f32,
+                    tempo: self.tempo,
                     // start_freq: 440.0 / (2.0).pow(((event.note as f32) / 12.0)),
                     // start_amp: (event.velocity as f32) / 128.0
                 });
             }
-            MidiEvent::NoteOff { ch, note, .. } => {
+            MidiEvent::NoteOff { ch, note, velocity } => {
                 // Try to find a matching playing note
                 let mut pos = None;
                 for n in 0..self.playing_notes.len() {
@@ -94,7 +104,6 @@ impl Handler for MidiHandler {
                         let end_time = u64::from(self.cur_time) /  (self.time_base as u64) * self.tempo;
                         //let duration = end_time - start_time;
                         let freq = 440.0 * (2.0_f32).powf((f32::from(pn.note) - 69.0) / 12.0);
-                        let _start_amp = f32::from(pn.velocity) / 128.0;
                         // let frequencies = Envelope::new(vec![EnvPhase {
                         //     start_time: 0,
                         //     end_time: duration,
@@ -114,8 +123,12 @@ impl Handler for MidiHandler {
                             program: pn.program,
                             start_time,
                             end_time,
+                            start_beat: pn.start_beat,
+                            tempo: pn.tempo,
                             midi_note: pn.note,
                             freq,
+                            velocity: pn.velocity,
+                            release_velocity: *velocity,
                             // amplitudes,
                         });
                         self.playing_notes.remove(n);
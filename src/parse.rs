@@ -1,14 +1,24 @@
-use serde::{Deserialize, Serialize};
+use serde::de::{Deserialize, Deserializer, Error};
+use serde::ser::{Serialize, Serializer};
+
 use std::collections::HashMap;
 
+use crate::easing::Easing;
+use crate::waveform::parse::{parse_quantity, Unit};
+
 #[derive(Serialize, Deserialize)]
 pub struct JSONArrangement {
     pub metadata: JSONMetadata,
+    #[serde(default)]
     pub sample_banks: Vec<JSONSampleBank>,
+    #[serde(default)]
+    pub functions: Vec<JSONFunction>,
     pub waveforms: Vec<JSONWaveform>,
     pub envelopes: Vec<JSONEnvelope>,
     pub instruments: Vec<JSONInstrument>,
     pub outputs: Vec<JSONOutput>,
+    #[serde(default)]
+    pub seed: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,7 +30,16 @@ pub struct JSONMetadata {
 #[derive(Serialize, Deserialize)]
 pub struct JSONSampleBank {
     pub name: String,
-    pub files: HashMap<String, String>
+    pub files: HashMap<String, String>,
+    #[serde(default)]
+    pub play_to_end: bool
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JSONFunction {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: String
 }
 
 #[derive(Serialize, Deserialize)]
@@ -32,16 +51,97 @@ pub struct JSONWaveform {
 #[derive(Serialize, Deserialize)]
 pub struct JSONEnvelope {
     pub name: String,
-    pub phases: Vec<JSONEnvelopePhase>
+    // Without phases, the envelope is an ADSR envelope made from attack,
+    // decay, sustain and release
+    #[serde(default)]
+    pub phases: Option<Vec<JSONEnvelopePhase>>,
+    #[serde(default, deserialize_with = "seconds")]
+    pub attack: f32,
+    #[serde(default, deserialize_with = "seconds")]
+    pub decay: f32,
+    #[serde(default = "default_sustain", deserialize_with = "level")]
+    pub sustain: f32,
+    // Either a release section or just its duration
+    #[serde(default, deserialize_with = "release")]
+    pub release: Option<JSONRelease>,
+    #[serde(default, rename = "loop")]
+    pub looping: Option<JSONLoop>
+}
+
+// Repeats the phases from index start to index end, inclusive, count times,
+// or until the end of the note if there is no count.
+#[derive(Serialize, Deserialize)]
+pub struct JSONLoop {
+    pub start: usize,
+    pub end: usize,
+    #[serde(default)]
+    pub count: Option<u32>
+}
+
+fn default_sustain() -> f32 {
+    1.0
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct JSONEnvelopePhase {
-    pub start_time: f32,
-    pub end_time: f32,
+    #[serde(default)]
+    pub timing: JSONTiming,
+    #[serde(deserialize_with = "phase_time")]
+    pub start_time: JSONPhaseTime,
+    #[serde(deserialize_with = "phase_time")]
+    pub end_time: JSONPhaseTime,
+    #[serde(deserialize_with = "level")]
     pub start_val: f32,
+    // How much the value changes over the phase, whether it follows an
+    // easing function or a curve. As a difference it takes no unit.
     pub end_val: f32,
-    pub ease_fn: String
+    #[serde(default = "default_ease_fn")]
+    pub ease_fn: Easing,
+    // An equation over t, from 0 at the start of the phase to 1 at the end,
+    // which replaces the easing function
+    #[serde(default)]
+    pub curve: Option<String>
+}
+
+// When an envelope phase starts or ends. Plain numbers are measured as
+// given by the phase's timing, while times with a unit, such as "20ms", are
+// always seconds.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum JSONPhaseTime {
+    Number(f32),
+    Seconds(f32)
+}
+
+// What the times of an envelope phase are measured in
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum JSONTiming {
+    // Fractions of the note's duration
+    #[default]
+    Proportional,
+    // Seconds from the start of the note
+    Absolute,
+    // Seconds from the end of the note
+    NoteOff,
+    // Quarter notes from the start of the note
+    Beats
+}
+
+
+// Runs for `duration` seconds after the end of the note, easing from the
+// envelope's value at the end of the note to `end_val`.
+#[derive(Serialize, Deserialize)]
+pub struct JSONRelease {
+    #[serde(deserialize_with = "seconds")]
+    pub duration: f32,
+    #[serde(default, deserialize_with = "level")]
+    pub end_val: f32,
+    #[serde(default = "default_ease_fn")]
+    pub ease_fn: Easing
+}
+
+fn default_ease_fn() -> Easing {
+    Easing::Linear
 }
 
 #[derive(Serialize, Deserialize)]
@@ -51,16 +151,166 @@ pub struct JSONInstrument {
     pub midi_percussion: bool,
     pub carrier: String,
     pub am: Vec<JSONModulator>,
+    #[serde(default)]
+    pub fm: Vec<JSONModulator>,
+    #[serde(default)]
+    pub pitch: Vec<JSONPitchModulator>,
+    // "fixed", "linear", "exponential", "logarithmic", or an equation
+    // over velocity
+    #[serde(default = "default_velocity_curve")]
+    pub velocity_curve: String,
+    // In decibels
+    #[serde(default = "default_velocity_range", deserialize_with = "decibels")]
+    pub velocity_range: f32,
+}
+
+fn default_velocity_curve() -> String {
+    String::from("fixed")
+}
+
+fn default_velocity_range() -> f32 {
+    40.0
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct JSONModulator {
     pub modulator: String,
+    #[serde(deserialize_with = "level")]
     pub depth: f32
 }
 
+// Bends the pitch of the carrier by depth semitones for each unit of the
+// modulator's value, or, with ratio, multiplies its frequency by the value.
+#[derive(Serialize, Deserialize)]
+pub struct JSONPitchModulator {
+    pub modulator: String,
+    #[serde(default = "default_pitch_depth", deserialize_with = "semitones")]
+    pub depth: f32,
+    #[serde(default)]
+    pub ratio: bool
+}
+
+fn default_pitch_depth() -> f32 {
+    1.0
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct JSONOutput {
     pub output_file: String,
     pub channels: Vec<u8>
 }
+
+// A number, or a string holding a number with a unit, such as "-6db".
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JSONQuantity {
+    Number(f32),
+    Text(String)
+}
+
+// Levels can be given as a plain number or as a gain in decibels, as in
+// "-6db", which is converted to the same scale as a plain number.
+fn level<'de, D: Deserializer<'de>>(d: D) -> Result<f32, D::Error> {
+    let s = match JSONQuantity::deserialize(d)? {
+        JSONQuantity::Number(n) => return Ok(n),
+        JSONQuantity::Text(s) => s
+    };
+    match parse_quantity(&s) {
+        Ok((n, None)) | Ok((n, Some(Unit::Decibels))) => Ok(n),
+        Ok(_) => Err(D::Error::custom(format!("invalid level '{}': expected no unit or 'db'", s))),
+        Err(e) => Err(D::Error::custom(format!("invalid level '{}': {}", s, e)))
+    }
+}
+
+// Times in seconds can be given as a plain number or with a unit, as in
+// "200ms".
+fn seconds<'de, D: Deserializer<'de>>(d: D) -> Result<f32, D::Error> {
+    to_seconds(JSONQuantity::deserialize(d)?)
+}
+
+fn to_seconds<E: Error>(q: JSONQuantity) -> Result<f32, E> {
+    let s = match q {
+        JSONQuantity::Number(n) => return Ok(n),
+        JSONQuantity::Text(s) => s
+    };
+    match parse_quantity(&s) {
+        Ok((n, None)) => Ok(n),
+        Ok((n, Some(unit))) if unit.is_time() => Ok(n),
+        Ok(_) => Err(E::custom(format!("invalid time '{}': expected a unit of 's' or 'ms'", s))),
+        Err(e) => Err(E::custom(format!("invalid time '{}': {}", s, e)))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JSONReleaseForm {
+    Duration(JSONQuantity),
+    Release(JSONRelease)
+}
+
+// A release given as just a duration fades linearly to zero.
+fn release<'de, D: Deserializer<'de>>(d: D) -> Result<Option<JSONRelease>, D::Error> {
+    match JSONReleaseForm::deserialize(d)? {
+        JSONReleaseForm::Release(release) => Ok(Some(release)),
+        JSONReleaseForm::Duration(duration) => Ok(Some(JSONRelease {
+            duration: to_seconds(duration)?,
+            end_val: 0.0,
+            ease_fn: default_ease_fn()
+        }))
+    }
+}
+
+// Intervals can be given in semitones as a plain number, or with a unit,
+// as in "+7st" or "50ct".
+fn semitones<'de, D: Deserializer<'de>>(d: D) -> Result<f32, D::Error> {
+    let s = match JSONQuantity::deserialize(d)? {
+        JSONQuantity::Number(n) => return Ok(n),
+        JSONQuantity::Text(s) => s
+    };
+    match parse_quantity(&s) {
+        Ok((n, None)) => Ok(n),
+        Ok((n, Some(Unit::Semitones))) | Ok((n, Some(Unit::Cents))) => Ok(12.0 * n.log2()),
+        Ok(_) => Err(D::Error::custom(format!("invalid interval '{}': expected a unit of 'st' or 'ct'", s))),
+        Err(e) => Err(D::Error::custom(format!("invalid interval '{}': {}", s, e)))
+    }
+}
+
+// Decibels can be given as a plain number or with a unit, as in "-6db".
+fn decibels<'de, D: Deserializer<'de>>(d: D) -> Result<f32, D::Error> {
+    let s = match JSONQuantity::deserialize(d)? {
+        JSONQuantity::Number(n) => return Ok(n),
+        JSONQuantity::Text(s) => s
+    };
+    match parse_quantity(&s) {
+        Ok((n, None)) => Ok(n),
+        Ok((n, Some(Unit::Decibels))) => Ok(20.0 * n.log10()),
+        Ok(_) => Err(D::Error::custom(format!("invalid level '{}': expected a unit of 'db'", s))),
+        Err(e) => Err(D::Error::custom(format!("invalid level '{}': {}", s, e)))
+    }
+}
+
+fn phase_time<'de, D: Deserializer<'de>>(d: D) -> Result<JSONPhaseTime, D::Error> {
+    let s = match JSONQuantity::deserialize(d)? {
+        JSONQuantity::Number(n) => return Ok(JSONPhaseTime::Number(n)),
+        JSONQuantity::Text(s) => s
+    };
+    match parse_quantity(&s) {
+        Ok((n, None)) => Ok(JSONPhaseTime::Number(n)),
+        Ok((n, Some(unit))) if unit.is_time() => Ok(JSONPhaseTime::Seconds(n)),
+        Ok(_) => Err(D::Error::custom(format!("invalid time '{}': expected a unit of 's' or 'ms'", s))),
+        Err(e) => Err(D::Error::custom(format!("invalid time '{}': {}", s, e)))
+    }
+}
+
+// Easing functions are written by name, as in "SineInOut" or "Steps(4)".
+impl Serialize for Easing {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Easing {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Easing, D::Error> {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+}
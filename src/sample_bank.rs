@@ -1,20 +1,24 @@
 use hound;
 use std::collections::HashMap;
 
-use crate::samplegen::{Params, SampleGen};
+use crate::samplegen::{Param, Params, SampleGen};
 
 #[derive(Clone)]
 pub struct SampleBank {
     pub name: String,
     pub files: HashMap<String, String>,
+    // Whether notes play until the end of their sample, rather than
+    // stopping at the end of the note
+    pub play_to_end: bool,
     cache: HashMap<String, Vec<f32>>
 }
 
 impl SampleBank {
-    pub fn new(name: String, files: HashMap<String, String>) -> SampleBank{
+    pub fn new(name: String, files: HashMap<String, String>, play_to_end: bool) -> SampleBank{
         SampleBank {
             name,
             files,
+            play_to_end,
             cache: HashMap::new()
         }
     }
@@ -31,11 +35,11 @@ impl SampleBank {
                 // out of samples - push silence
                 output.push(0.0);
             } else if (loc - loc_floor) < 0.5 {
-                let sample = snd[loc_floor as usize] as f32;
-                output.push(sample as f32);
+                let sample = snd[loc_floor as usize];
+                output.push(sample);
             } else {
-                let sample = snd[loc_floor as usize + 1] as f32;
-                output.push(sample as f32);
+                let sample = snd[loc_floor as usize + 1];
+                output.push(sample);
             }
         }
         output
@@ -44,20 +48,20 @@ impl SampleBank {
 
 impl SampleGen for SampleBank {
     fn cache(&mut self, p: &Params) {
-        let midi_note = p["midi_note"].to_string();
+        let midi_note = p[Param::MidiNote].to_string();
         if !self.cache.contains_key(&midi_note) {
             if self.files.contains_key(&midi_note) {
                 let mut reader = hound::WavReader::open(self.files.get(&midi_note).unwrap()).unwrap();
                 let snd = reader.samples::<i16>().map(|s| s.unwrap() as f32).collect();
                 self.cache.insert(midi_note, snd);
             } else {
-                let str = format!("WARNING: Could not find sample with MIDI pitch {} - attempting to resample", p["midi_note"]);
+                let str = format!("WARNING: Could not find sample with MIDI pitch {} - attempting to resample", p[Param::MidiNote]);
                 dbg!(str);
-                let target_freq = 440.0 * (2.0_f32).powf((p["midi_note"] - 69.0) / 12.0);
+                let target_freq = 440.0 * (2.0_f32).powf((p[Param::MidiNote] - 69.0) / 12.0);
                 // find the closest midi note
                 for i in 0..127 {
-                    if self.files.contains_key(&(p["midi_note"] as u8 + i).to_string()) {
-                        let closest_note = f32::from(p["midi_note"] as u8 + i);
+                    if self.files.contains_key(&(p[Param::MidiNote] as u8 + i).to_string()) {
+                        let closest_note = f32::from(p[Param::MidiNote] as u8 + i);
                         let closest_freq = 440.0 * (2.0_f32).powf((closest_note - 69.0) / 12.0);
                         let mult = target_freq / closest_freq;
                         let mut reader = hound::WavReader::open(self.files.get(&closest_note.to_string()).unwrap()).unwrap();
@@ -65,8 +69,8 @@ impl SampleGen for SampleBank {
                         let snd = self.resample(orig_snd, mult);
                         self.cache.insert(midi_note, snd);
                         break;
-                    } else if self.files.contains_key(&(p["midi_note"] as u8 - i).to_string()) {
-                        let closest_note = f32::from(p["midi_note"] as u8 - i);
+                    } else if self.files.contains_key(&(p[Param::MidiNote] as u8 - i).to_string()) {
+                        let closest_note = f32::from(p[Param::MidiNote] as u8 - i);
                         let closest_freq = 440.0 * (2.0_f32).powf((closest_note - 69.0) / 12.0);
                         let mult = target_freq / closest_freq;
                         let mut reader = hound::WavReader::open(self.files.get(&closest_note.to_string()).unwrap()).unwrap();
@@ -80,13 +84,18 @@ impl SampleGen for SampleBank {
         }
     }
 
-    fn get_sample(&self, p: &Params) -> Option<f32> {
-        let midi_note = p["midi_note"].to_string();
+    fn get_sample(&mut self, p: &Params) -> Option<f32> {
+        let midi_note = p[Param::MidiNote].to_string();
         if self.cache.contains_key(&midi_note) {
-            let sample = p["sample"] as usize;
+            // The position may fall between samples when the pitch is bent
+            let sample = p[Param::Sample] as usize;
+            let frac = p[Param::Sample] - sample as f32;
             let snd = self.cache.get(&midi_note).unwrap();
             if snd.len() > sample {
-                Some(self.cache.get(&midi_note).unwrap()[sample] as f32)
+                match snd.get(sample + 1) {
+                    Some(next) if frac > 0.0 => Some(snd[sample] + (next - snd[sample]) * frac),
+                    _ => Some(snd[sample])
+                }
             } else {
                 // The note is longer than the sample - return silence
                 Some(0.0)
@@ -97,7 +106,14 @@ impl SampleGen for SampleBank {
         }
     }
 
-    fn get_mod_sample(&self, _p: &Params) -> Option<f32> {
+    fn get_mod_sample(&mut self, _p: &Params) -> Option<f32> {
         None
     }
+
+    fn release(&self, p: &Params) -> u64 {
+        match self.cache.get(&p[Param::MidiNote].to_string()) {
+            Some(snd) if self.play_to_end => (snd.len() as u64).saturating_sub(p[Param::Duration] as u64),
+            _ => 0
+        }
+    }
 }
@@ -1,13 +1,148 @@
-use std::collections::HashMap;
+use std::ops::{Index, IndexMut};
 
-pub type Params = HashMap<String, f32>;
+/// The inputs the renderer supplies to sample generators for every sample.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Param {
+    Duration,
+    Sample,
+    Time,
+    Rate,
+    MidiNote,
+    Frequency,
+    X,
+    Phase,
+    /// Frequency the carrier is played at once the fm and pitch
+    /// modulators have bent it, which is `Frequency` without them
+    CarrierFrequency,
+    /// MIDI velocity of the note-on, from 0 to 1
+    Velocity,
+    /// MIDI velocity of the note-off, from 0 to 1
+    ReleaseVelocity,
+    Channel,
+    Program,
+    /// When the note starts, in seconds from the start of the song
+    NoteStart,
+    /// When the note starts, in quarter notes from the start of the song
+    NoteBeat,
+    /// Seconds from the start of the song to the current sample
+    SongTime,
+    /// Quarter notes per minute when the note starts
+    Tempo,
+}
+
+impl Param {
+    pub const ALL: [Param; 17] = [
+        Param::Duration,
+        Param::Sample,
+        Param::Time,
+        Param::Rate,
+        Param::MidiNote,
+        Param::Frequency,
+        Param::X,
+        Param::Phase,
+        Param::CarrierFrequency,
+        Param::Velocity,
+        Param::ReleaseVelocity,
+        Param::Channel,
+        Param::Program,
+        Param::NoteStart,
+        Param::NoteBeat,
+        Param::SongTime,
+        Param::Tempo,
+    ];
+
+    /// The name used to refer to this parameter in waveform equations.
+    pub fn name(self) -> &'static str {
+        match self {
+            Param::Duration => "duration",
+            Param::Sample => "sample",
+            Param::Time => "time",
+            Param::Rate => "rate",
+            Param::MidiNote => "midi_note",
+            Param::Frequency => "freq",
+            Param::X => "x",
+            Param::Phase => "phase",
+            Param::CarrierFrequency => "carrier_freq",
+            Param::Velocity => "velocity",
+            Param::ReleaseVelocity => "release_velocity",
+            Param::Channel => "channel",
+            Param::Program => "program",
+            Param::NoteStart => "note_start",
+            Param::NoteBeat => "note_beat",
+            Param::SongTime => "song_time",
+            Param::Tempo => "tempo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Param> {
+        Param::ALL.iter().copied().find(|p| p.name() == name)
+    }
+
+    /// Whether the parameter has the same value for every sample of a note.
+    pub fn is_per_note(self) -> bool {
+        match self {
+            Param::Duration
+            | Param::Rate
+            | Param::MidiNote
+            | Param::Frequency
+            | Param::Velocity
+            | Param::ReleaseVelocity
+            | Param::Channel
+            | Param::Program
+            | Param::NoteStart
+            | Param::NoteBeat
+            | Param::Tempo => true,
+            Param::Sample
+            | Param::Time
+            | Param::X
+            | Param::Phase
+            | Param::CarrierFrequency
+            | Param::SongTime => false,
+        }
+    }
+}
+
+/// The value of every `Param` for the sample being rendered.
+#[derive(Debug, Default, Clone)]
+pub struct Params {
+    values: [f32; Param::ALL.len()],
+    /// Seed for random number generators, which is different for every
+    /// note but the same every time the arrangement is rendered.
+    pub seed: u64,
+}
+
+impl Params {
+    pub fn new() -> Params {
+        Default::default()
+    }
+}
+
+impl Index<Param> for Params {
+    type Output = f32;
+
+    fn index(&self, p: Param) -> &f32 {
+        &self.values[p as usize]
+    }
+}
+
+impl IndexMut<Param> for Params {
+    fn index_mut(&mut self, p: Param) -> &mut f32 {
+        &mut self.values[p as usize]
+    }
+}
 
 pub trait SampleGen {
     // Initialize audio cache if possible and necessary, otherwise do nothing.
     fn cache(&mut self, p: &Params);
 
-    fn get_sample(&self, p: &Params) -> Option<f32>;
+    fn get_sample(&mut self, p: &Params) -> Option<f32>;
 
     // Return a value between 0.0 and 1.0 suitable for a modulator multiplied by depth.
-    fn get_mod_sample(&self, p: &Params) -> Option<f32>;
+    fn get_mod_sample(&mut self, p: &Params) -> Option<f32>;
+
+    // How many samples the generator keeps sounding for after the end of
+    // the note, once it has been cached for the note.
+    fn release(&self, _p: &Params) -> u64 {
+        0
+    }
 }
@@ -0,0 +1,772 @@
+use std::f32::consts::PI;
+
+pub use crate::error::{EvalError, EvalResult};
+
+use super::function::FunctionTable;
+use super::noise::{hash, value_noise, Generator, Noise};
+use super::oscillator::Oscillator;
+use super::parse::{Expression, InfixOp, PrefixOp};
+use crate::samplegen::{Param, Params};
+
+/// Built-in functions called with a bracketed argument list.
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Builtin {
+    Min,
+    Max,
+    Clamp,
+    Pow,
+    Atan2,
+    RawAtan2,
+    Lerp,
+    Harmonics,
+    Table,
+    Step,
+}
+
+impl Builtin {
+    fn from_name(name: &str) -> Option<Builtin> {
+        match name {
+            "min" => Some(Builtin::Min),
+            "max" => Some(Builtin::Max),
+            "clamp" => Some(Builtin::Clamp),
+            "pow" => Some(Builtin::Pow),
+            "atan2" => Some(Builtin::Atan2),
+            "ratan2" => Some(Builtin::RawAtan2),
+            "lerp" => Some(Builtin::Lerp),
+            "harmonics" => Some(Builtin::Harmonics),
+            "table" => Some(Builtin::Table),
+            "step" => Some(Builtin::Step),
+            _ => None,
+        }
+    }
+
+    fn apply(self, a: &[f32]) -> f32 {
+        match self {
+            Builtin::Min => a[0].min(a[1]),
+            Builtin::Max => a[0].max(a[1]),
+            Builtin::Clamp => a[0].max(a[1]).min(a[2]),
+            Builtin::Pow => a[0].powf(a[1]),
+            // Angles are measured in multiples of PI, as with `sin` and `cos`
+            Builtin::Atan2 => a[0].atan2(a[1]) / PI,
+            Builtin::RawAtan2 => a[0].atan2(a[1]),
+            Builtin::Lerp => a[0] + (a[1] - a[0]) * a[2],
+            // The rest of the arguments are the values of an array
+            Builtin::Harmonics => harmonics(a[0], &a[1..]),
+            Builtin::Table => {
+                let pos = a[0].floor();
+                let (v, w) = (entry(&a[1..], pos), entry(&a[1..], pos + 1.0));
+                v + (w - v) * (a[0] - pos)
+            }
+            Builtin::Step => entry(&a[1..], a[0].floor()),
+        }
+    }
+}
+
+/// Sum of the harmonics of `sin(x)` with the given amplitudes, the first
+/// being the fundamental.
+fn harmonics(x: f32, amplitudes: &[f32]) -> f32 {
+    // sin(kx) = 2cos(x)sin((k-1)x) - sin((k-2)x)
+    let two_cos = 2.0 * (x * PI).cos();
+    let (mut previous, mut current) = (0.0, (x * PI).sin());
+    let mut sum = 0.0;
+    for a in amplitudes {
+        sum += a * current;
+        let next = two_cos * current - previous;
+        previous = current;
+        current = next;
+    }
+    sum
+}
+
+/// The entry of an array at a whole number index, wrapping around at
+/// either end, or 0.0 if it is empty.
+fn entry(values: &[f32], index: f32) -> f32 {
+    if values.is_empty() || !index.is_finite() {
+        return 0.0;
+    }
+    values[index.rem_euclid(values.len() as f32) as usize % values.len()]
+}
+
+impl PrefixOp {
+    pub fn apply(self, x: f32) -> f32 {
+        match self {
+            PrefixOp::Negate => -x,
+            PrefixOp::Abs => x.abs(),
+            PrefixOp::Sgn => x.signum(),
+            PrefixOp::Sin => (x * PI).sin(),
+            PrefixOp::Cos => (x * PI).cos(),
+            PrefixOp::Tan => (x * PI).tan(),
+            PrefixOp::RawSin => x.sin(),
+            PrefixOp::RawCos => x.cos(),
+            PrefixOp::RawTan => x.tan(),
+            PrefixOp::Tanh => x.tanh(),
+            PrefixOp::Exp => x.exp(),
+            PrefixOp::Ln => x.ln(),
+            PrefixOp::Log2 => x.log2(),
+            PrefixOp::Log10 => x.log10(),
+            PrefixOp::Sqrt => x.sqrt(),
+            PrefixOp::Floor => x.floor(),
+            PrefixOp::Ceil => x.ceil(),
+            PrefixOp::Round => x.round(),
+            PrefixOp::Fract => x - x.floor(),
+            PrefixOp::Not => truth(x == 0.0),
+        }
+    }
+}
+
+impl InfixOp {
+    pub fn apply(self, a: f32, b: f32) -> f32 {
+        match self {
+            InfixOp::Add => a + b,
+            InfixOp::Sub => a - b,
+            InfixOp::Mul => a * b,
+            InfixOp::Div => a / b,
+            InfixOp::Mod => a % b,
+            InfixOp::Pow => a.powf(b),
+            InfixOp::Less => truth(a < b),
+            InfixOp::LessEqual => truth(a <= b),
+            InfixOp::Greater => truth(a > b),
+            InfixOp::GreaterEqual => truth(a >= b),
+            InfixOp::Equal => truth(a == b),
+            InfixOp::NotEqual => truth(a != b),
+            InfixOp::And => truth(a != 0.0 && b != 0.0),
+            InfixOp::Or => truth(a != 0.0 || b != 0.0),
+        }
+    }
+}
+
+/// Apply the built-in function `name` to constant arguments, or return
+/// `None` if there is no such function.
+pub fn call_builtin(name: &str, args: &[f32]) -> Option<f32> {
+    Builtin::from_name(name).map(|f| f.apply(args))
+}
+
+/// The value recorded `n` samples before the last one in `buffer`,
+/// interpolating between samples, or 0.0 before the start of the note.
+fn sample_back(buffer: &[f32], n: f32) -> f32 {
+    let n = n.max(0.0);
+    let i = n.floor();
+    let back = |i: usize| {
+        buffer
+            .len()
+            .checked_sub(i + 1)
+            .map_or(0.0, |index| buffer[index])
+    };
+    let (a, b) = (back(i as usize), back(i as usize + 1));
+    a + (b - a) * (n - i)
+}
+
+/// The values recorded so far in the current note by a delay line or
+/// a waveform's history.
+#[derive(Clone, Debug)]
+struct Recording {
+    samples: Vec<f32>,
+    /// How many of the latest samples can be looked back at, or `None`
+    /// if that isn't known until the equation is evaluated.
+    keep: Option<usize>,
+}
+
+impl Recording {
+    fn new(keep: Option<usize>) -> Recording {
+        Recording {
+            samples: Vec::new(),
+            keep,
+        }
+    }
+
+    fn push(&mut self, x: f32) {
+        if let Some(keep) = self.keep {
+            // Drop old samples in batches, so each push stays cheap
+            if self.samples.len() >= 2 * keep {
+                self.samples.drain(..self.samples.len() - keep);
+            }
+        }
+        self.samples.push(x);
+    }
+}
+
+/// The number of samples which must be kept to look `n` samples back
+/// with `sample_back`, if `n` is a constant.
+fn samples_needed(n: &Expression) -> Option<usize> {
+    match n {
+        Expression::Number(n) if n.is_finite() => Some(n.max(0.0) as usize + 2),
+        _ => None,
+    }
+}
+
+/// Converts a boolean into the 1.0 or 0.0 used to represent it in equations.
+fn truth(b: bool) -> f32 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// A single instruction for the stack machine which evaluates a
+/// compiled expression.
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Op {
+    /// Push a constant.
+    Const(f32),
+    /// Push the value of a renderer input.
+    Param(Param),
+    /// Push the value of a local slot.
+    Local(usize),
+    /// Pop a value into a local slot.
+    SetLocal(usize),
+    /// Push a value computed once per note.
+    Hoisted(usize),
+    /// Pop a value computed once per note into its slot.
+    SetHoisted(usize),
+    /// Apply an operator to the top of the stack.
+    Prefix(PrefixOp),
+    /// Pop the right hand operand and apply an operator to it and the
+    /// new top of the stack.
+    Infix(InfixOp),
+    /// Replace the given number of arguments with the function's result.
+    Call(Builtin, usize),
+    /// Replace the oscillator's arguments with its next sample, using
+    /// the phase it was last called with, which is kept in the given
+    /// slot.
+    Oscillator(Oscillator, usize),
+    /// Push the next sample from the noise generator in the given slot.
+    Noise(Noise, usize),
+    /// Replace the top of the stack with the output of the waveform
+    /// whose history is in the given slot that many samples ago.
+    Prev(usize),
+    /// Copy the top of the stack, the output of a waveform, to the end
+    /// of the history in the given slot.
+    Record(usize),
+    /// Pop a number of samples and record the new top of the stack in
+    /// the given delay line, then replace it with the value recorded
+    /// that many samples ago.
+    Delay(usize),
+    /// Replace the top of the stack with the value noise at that point.
+    ValueNoise,
+    /// Replace the top of the stack with its truth value.
+    Truth,
+    /// Jump to the given instruction.
+    Jump(usize),
+    /// Pop the top of the stack, jumping if it is zero.
+    JumpIfZero(usize),
+    /// Jump if the top of the stack is false, leaving it as the result
+    /// of an `&&`. Otherwise pop it.
+    And(usize),
+    /// Jump if the top of the stack is true, leaving 1.0 as the result
+    /// of an `||`. Otherwise pop it.
+    Or(usize),
+    /// Fail, as an identifier has no value.
+    Unbound,
+}
+
+/// An expression compiled for fast repeated evaluation.
+///
+/// Identifiers are resolved to renderer inputs or local slots, calls to
+/// user-defined functions are inlined, and the result is a flat list
+/// of instructions for a stack machine. Subexpressions which only
+/// depend on the note being played are split out into a separate list
+/// of instructions, which is run once at the start of each note.
+#[derive(Clone, Debug)]
+pub struct Program {
+    ops: Vec<Op>,
+    setup: Vec<Op>,
+    stack: Vec<f32>,
+    locals: Vec<f32>,
+    hoisted: Vec<f32>,
+    /// The seed of the note being played.
+    seed: u64,
+    /// One noise generator for every call to `white()`, `pink()` or
+    /// `brown()`, reseeded at the start of each note.
+    generators: Vec<Generator>,
+    /// The phase each call to an oscillator was given at the previous
+    /// sample, or `None` at the start of each note.
+    phases: Vec<Option<f32>>,
+    /// The outputs of this waveform, and of each waveform it refers to,
+    /// so far in the current note.
+    histories: Vec<Recording>,
+    /// The values passed to each call to `delay` so far in the current
+    /// note.
+    delays: Vec<Recording>,
+}
+
+impl Program {
+    pub fn compile(exp: &Expression, functions: &FunctionTable) -> Program {
+        let mut c = Compiler {
+            functions,
+            ops: Vec::new(),
+            setup: Vec::new(),
+            scope: Vec::new(),
+            next_slot: 0,
+            slots: 0,
+            hoisted: 0,
+            hoisting: false,
+            generators: 0,
+            oscillators: 0,
+            history: 0,
+            histories: vec![Some(0)],
+            delays: Vec::new(),
+        };
+        c.expression(exp);
+        c.record_history();
+        Program {
+            ops: c.ops,
+            setup: c.setup,
+            stack: Vec::new(),
+            locals: vec![0.0; c.slots],
+            hoisted: vec![0.0; c.hoisted],
+            seed: 0,
+            generators: (0..c.generators)
+                .map(|i| Generator::new(i as u64))
+                .collect(),
+            phases: vec![None; c.oscillators],
+            histories: c.histories.into_iter().map(Recording::new).collect(),
+            delays: c.delays.into_iter().map(Recording::new).collect(),
+        }
+    }
+
+    /// Evaluate the subexpressions which only depend on the note being
+    /// played. This must be called at the start of every note.
+    pub fn prepare(&mut self, p: &Params) {
+        self.seed = p.seed;
+        for (i, generator) in self.generators.iter_mut().enumerate() {
+            *generator = Generator::new(hash(p.seed, i as u64));
+        }
+        for phase in &mut self.phases {
+            *phase = None;
+        }
+        for buffer in self.histories.iter_mut().chain(&mut self.delays) {
+            buffer.samples.clear();
+        }
+        // Hoisted subexpressions never refer to unbound identifiers
+        let _ = self.run(true, p);
+    }
+
+    /// Evaluate the program for a single sample.
+    pub fn eval(&mut self, p: &Params) -> EvalResult<f32> {
+        self.run(false, p)?;
+        self.stack.pop().ok_or(EvalError::Incomplete)
+    }
+
+    fn run(&mut self, setup: bool, p: &Params) -> EvalResult<()> {
+        let Program {
+            ops,
+            setup: setup_ops,
+            stack,
+            locals,
+            hoisted,
+            seed,
+            generators,
+            phases,
+            histories,
+            delays,
+        } = self;
+        let ops = if setup { setup_ops } else { ops };
+        stack.clear();
+        let mut pc = 0;
+        while let Some(op) = ops.get(pc) {
+            pc += 1;
+            // Compiled programs are always balanced, so the stack can't
+            // underflow.
+            match *op {
+                Op::Const(n) => stack.push(n),
+                Op::Param(param) => stack.push(p[param]),
+                Op::Local(slot) => stack.push(locals[slot]),
+                Op::SetLocal(slot) => locals[slot] = stack.pop().unwrap(),
+                Op::Hoisted(slot) => stack.push(hoisted[slot]),
+                Op::SetHoisted(slot) => hoisted[slot] = stack.pop().unwrap(),
+                Op::Prefix(op) => {
+                    let x = stack.last_mut().unwrap();
+                    *x = op.apply(*x);
+                }
+                Op::Infix(op) => {
+                    let b = stack.pop().unwrap();
+                    let a = stack.last_mut().unwrap();
+                    *a = op.apply(*a, b);
+                }
+                Op::Call(f, argc) => {
+                    let base = stack.len() - argc;
+                    let n = f.apply(&stack[base..]);
+                    stack.truncate(base);
+                    stack.push(n);
+                }
+                Op::Oscillator(osc, slot) => {
+                    let base = stack.len() - osc.arity();
+                    let dt = Oscillator::step(stack[base], phases[slot], p);
+                    phases[slot] = Some(stack[base]);
+                    let n = osc.apply(&stack[base..], dt);
+                    stack.truncate(base);
+                    stack.push(n);
+                }
+                Op::Noise(kind, slot) => stack.push(generators[slot].next(kind)),
+                Op::Prev(slot) => {
+                    // The last value recorded was one sample ago
+                    let n = stack.last_mut().unwrap();
+                    *n = sample_back(&histories[slot].samples, *n - 1.0);
+                }
+                Op::Record(slot) => histories[slot].push(*stack.last().unwrap()),
+                Op::Delay(slot) => {
+                    let n = stack.pop().unwrap();
+                    let x = stack.last_mut().unwrap();
+                    delays[slot].push(*x);
+                    *x = sample_back(&delays[slot].samples, n);
+                }
+                Op::ValueNoise => {
+                    let t = stack.last_mut().unwrap();
+                    *t = value_noise(*seed, *t);
+                }
+                Op::Truth => {
+                    let x = stack.last_mut().unwrap();
+                    *x = truth(*x != 0.0);
+                }
+                Op::Jump(target) => pc = target,
+                Op::JumpIfZero(target) => {
+                    if stack.pop().unwrap() == 0.0 {
+                        pc = target;
+                    }
+                }
+                Op::And(target) => {
+                    let x = stack.last_mut().unwrap();
+                    if *x == 0.0 {
+                        *x = 0.0;
+                        pc = target;
+                    } else {
+                        stack.pop();
+                    }
+                }
+                Op::Or(target) => {
+                    let x = stack.last_mut().unwrap();
+                    if *x != 0.0 {
+                        *x = 1.0;
+                        pc = target;
+                    } else {
+                        stack.pop();
+                    }
+                }
+                Op::Unbound => return Err(EvalError::Incomplete),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the value of a local name is stored.
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Binding {
+    Local(usize),
+    Hoisted(usize),
+}
+
+/// Translates an expression tree into a `Program`.
+struct Compiler<'a> {
+    functions: &'a FunctionTable,
+    ops: Vec<Op>,
+    setup: Vec<Op>,
+    /// Local names visible to the expression being compiled and where
+    /// they are stored. Later entries shadow earlier ones.
+    scope: Vec<(&'a str, Binding)>,
+    /// The first slot not used by an enclosing binding.
+    next_slot: usize,
+    /// The total number of slots needed.
+    slots: usize,
+    /// The number of values computed once per note.
+    hoisted: usize,
+    /// Whether the instructions being emitted are run once per note.
+    hoisting: bool,
+    /// The number of noise generators needed.
+    generators: usize,
+    /// The number of calls to oscillators, each of which remembers its
+    /// previous phase.
+    oscillators: usize,
+    /// The history slot of the waveform whose body is being compiled.
+    history: usize,
+    /// How many samples each waveform history needs to keep, or `None`
+    /// if it isn't known.
+    histories: Vec<Option<usize>>,
+    /// How many samples each delay line needs to keep, or `None` if it
+    /// isn't known.
+    delays: Vec<Option<usize>>,
+}
+
+impl<'a> Compiler<'a> {
+    fn expression(&mut self, exp: &'a Expression) {
+        // Evaluate subexpressions which only depend on the note being
+        // played once per note, rather than once per sample.
+        let trivial = matches!(exp, Expression::Number(_) | Expression::Identifier(_));
+        if !self.hoisting && !trivial && self.is_per_note(exp) {
+            let slot = self.hoist(exp);
+            self.ops.push(Op::Hoisted(slot));
+            return;
+        }
+
+        match exp {
+            Expression::Number(n) => self.ops.push(Op::Const(*n)),
+            Expression::Identifier(name) => {
+                let op = match self.lookup(name) {
+                    Some(Binding::Local(slot)) => Op::Local(slot),
+                    Some(Binding::Hoisted(slot)) => Op::Hoisted(slot),
+                    None => Param::from_name(name).map_or(Op::Unbound, Op::Param),
+                };
+                self.ops.push(op);
+            }
+            Expression::Prefix(op, arg) => {
+                self.expression(arg);
+                self.ops.push(Op::Prefix(*op));
+            }
+            // Logical operators only evaluate their right hand side when needed
+            Expression::Infix(lhs, op @ InfixOp::And, rhs)
+            | Expression::Infix(lhs, op @ InfixOp::Or, rhs) => {
+                self.expression(lhs);
+                let jump = self.ops.len();
+                self.ops.push(if *op == InfixOp::And {
+                    Op::And(0)
+                } else {
+                    Op::Or(0)
+                });
+                self.expression(rhs);
+                self.ops.push(Op::Truth);
+                self.patch(jump);
+            }
+            Expression::Infix(lhs, op, rhs) => {
+                self.expression(lhs);
+                self.expression(rhs);
+                self.ops.push(Op::Infix(*op));
+            }
+            Expression::Call(name, args) => {
+                for arg in args {
+                    self.expression(arg);
+                }
+                match self.functions.get(name) {
+                    Some(f) => {
+                        // Bind the arguments to the parameters in fresh
+                        // slots, then inline the body in a scope which
+                        // only contains the parameters.
+                        let base = self.next_slot;
+                        for slot in (base..base + args.len()).rev() {
+                            self.ops.push(Op::SetLocal(slot));
+                        }
+                        let params = f.params.iter().map(|s| s.as_str());
+                        let params = params.zip((base..).map(Binding::Local));
+                        let outer = std::mem::replace(&mut self.scope, params.collect());
+                        self.reserve(args.len());
+                        if self.functions.is_waveform(name) {
+                            // Waveforms referred to by name keep their
+                            // own history for `prev`.
+                            let outer = std::mem::replace(&mut self.history, self.histories.len());
+                            self.histories.push(Some(0));
+                            self.expression(&f.body);
+                            self.record_history();
+                            self.history = outer;
+                        } else {
+                            self.expression(&f.body);
+                        }
+                        self.next_slot = base;
+                        self.scope = outer;
+                    }
+                    None => {
+                        // The parser has already checked the arity of
+                        // built-in functions.
+                        let op = if let Some(f) = Builtin::from_name(name) {
+                            Op::Call(f, args.len())
+                        } else if let Some(osc) = Oscillator::from_name(name) {
+                            self.oscillators += 1;
+                            Op::Oscillator(osc, self.oscillators - 1)
+                        } else if let Some(noise) = Noise::from_name(name) {
+                            self.noise(noise)
+                        } else if name == "prev" {
+                            // Calls to `prev` in the same waveform share its
+                            // history, so it keeps enough for the furthest.
+                            let keep = &mut self.histories[self.history];
+                            *keep = keep.and_then(|k| Some(k.max(samples_needed(&args[0])?)));
+                            Op::Prev(self.history)
+                        } else if name == "delay" {
+                            self.delays.push(samples_needed(&args[1]));
+                            Op::Delay(self.delays.len() - 1)
+                        } else {
+                            Op::Unbound
+                        };
+                        self.ops.push(op);
+                    }
+                }
+            }
+            Expression::Conditional(cond, then, otherwise) => {
+                self.expression(cond);
+                let to_otherwise = self.ops.len();
+                self.ops.push(Op::JumpIfZero(0));
+                self.expression(then);
+                let to_end = self.ops.len();
+                self.ops.push(Op::Jump(0));
+                self.patch(to_otherwise);
+                self.expression(otherwise);
+                self.patch(to_end);
+            }
+            Expression::Let(name, value, body) if !self.hoisting && self.is_per_note(value) => {
+                let slot = self.hoist(value);
+                self.scope.push((name, Binding::Hoisted(slot)));
+                self.expression(body);
+                self.scope.pop();
+            }
+            Expression::Let(name, value, body) => {
+                self.expression(value);
+                let slot = self.next_slot;
+                self.ops.push(Op::SetLocal(slot));
+                self.reserve(1);
+                self.scope.push((name, Binding::Local(slot)));
+                self.expression(body);
+                self.scope.pop();
+                self.next_slot = slot;
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Binding> {
+        self.scope
+            .iter()
+            .rev()
+            .find(|(local, _)| *local == name)
+            .map(|(_, binding)| *binding)
+    }
+
+    /// Compile an expression into the instructions run at the start of
+    /// each note, returning the slot its value is stored in.
+    fn hoist(&mut self, exp: &'a Expression) -> usize {
+        let slot = self.hoisted;
+        self.hoisted += 1;
+        std::mem::swap(&mut self.ops, &mut self.setup);
+        self.hoisting = true;
+        self.expression(exp);
+        self.ops.push(Op::SetHoisted(slot));
+        self.hoisting = false;
+        std::mem::swap(&mut self.ops, &mut self.setup);
+        slot
+    }
+
+    /// Whether an expression has the same value for every sample of a note.
+    fn is_per_note(&self, exp: &'a Expression) -> bool {
+        let mut scope = self
+            .scope
+            .iter()
+            .map(|(name, binding)| (*name, matches!(binding, Binding::Hoisted(_))))
+            .collect();
+        self.per_note(exp, &mut scope)
+    }
+
+    fn per_note(&self, exp: &'a Expression, scope: &mut Vec<(&'a str, bool)>) -> bool {
+        match exp {
+            Expression::Number(_) => true,
+            Expression::Identifier(name) => {
+                match scope.iter().rev().find(|(local, _)| local == name) {
+                    Some((_, per_note)) => *per_note,
+                    None => Param::from_name(name).is_some_and(Param::is_per_note),
+                }
+            }
+            Expression::Prefix(_, arg) => self.per_note(arg, scope),
+            Expression::Infix(lhs, _, rhs) => {
+                self.per_note(lhs, scope) && self.per_note(rhs, scope)
+            }
+            Expression::Call(name, args) => {
+                if !args.iter().all(|arg| self.per_note(arg, scope)) {
+                    return false;
+                }
+                match self.functions.get(name) {
+                    Some(f) => {
+                        let mut params = f.params.iter().map(|s| (s.as_str(), true)).collect();
+                        self.per_note(&f.body, &mut params)
+                    }
+                    // Noise generators change from sample to sample
+                    None => {
+                        Builtin::from_name(name).is_some()
+                            || Oscillator::from_name(name).is_some()
+                            || Noise::from_name(name) == Some(Noise::Value)
+                    }
+                }
+            }
+            Expression::Conditional(cond, then, otherwise) => {
+                self.per_note(cond, scope)
+                    && self.per_note(then, scope)
+                    && self.per_note(otherwise, scope)
+            }
+            Expression::Let(name, value, body) => {
+                let value = self.per_note(value, scope);
+                scope.push((name, value));
+                let body = self.per_note(body, scope);
+                scope.pop();
+                body
+            }
+        }
+    }
+
+    /// The instruction for a call to a noise function, claiming a
+    /// generator for it if it needs one.
+    fn noise(&mut self, noise: Noise) -> Op {
+        if noise.is_stateful() {
+            self.generators += 1;
+            Op::Noise(noise, self.generators - 1)
+        } else {
+            Op::ValueNoise
+        }
+    }
+
+    /// Record the output of the waveform just compiled, if its body
+    /// refers to its previous outputs.
+    fn record_history(&mut self) {
+        if self.ops.contains(&Op::Prev(self.history)) {
+            self.ops.push(Op::Record(self.history));
+        }
+    }
+
+    /// Claim `n` slots above those already in use.
+    fn reserve(&mut self, n: usize) {
+        self.next_slot += n;
+        self.slots = self.slots.max(self.next_slot);
+    }
+
+    /// Point the jump at `index` to the next instruction to be emitted.
+    fn patch(&mut self, index: usize) {
+        let target = self.ops.len();
+        self.ops[index] = match self.ops[index] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfZero(_) => Op::JumpIfZero(target),
+            Op::And(_) => Op::And(target),
+            Op::Or(_) => Op::Or(target),
+            op => op,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::waveform::parse::parse_single;
+
+    /// The first `n` samples of `equation` for a note of the given
+    /// frequency, where `x` advances as it does in the renderer.
+    fn render(equation: &str, freq: f32, n: usize) -> Vec<f32> {
+        let functions = FunctionTable::new();
+        let mut program = Program::compile(&parse_single(equation).unwrap(), &functions);
+        let mut p = Params::new();
+        p[Param::Rate] = 44100.0;
+        p[Param::Frequency] = freq;
+        p[Param::CarrierFrequency] = freq;
+        program.prepare(&p);
+        (0..n)
+            .map(|s| {
+                p[Param::Sample] = s as f32;
+                p[Param::X] = s as f32 * freq / 44100.0;
+                program.eval(&p).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn oscillators_follow_scaled_phases() {
+        // Doubling the phase is the same as doubling the frequency
+        for osc in &["blsaw", "bltri"] {
+            let scaled = render(&format!("{}(2*x)", osc), 2793.8, 200);
+            let direct = render(&format!("{}(x)", osc), 2.0 * 2793.8, 200);
+            for (a, b) in scaled.iter().zip(&direct) {
+                assert!((a - b).abs() < 1e-3, "{}: {} != {}", osc, a, b);
+            }
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+
+pub use crate::error::{EquationError, EquationResult, FunctionError, FunctionResult};
+
+use super::names::{check_names, resolve_waveforms};
+use super::parse::{constant, function_arity, optimise, Expression, InfixOp};
+use crate::samplegen::Param;
+
+/// A user-defined function which can be called from any waveform
+/// equation in the arrangement.
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub params: Vec<String>,
+    pub body: Expression,
+}
+
+/// The user-defined functions of an arrangement, indexed by name.
+///
+/// Waveforms are also kept here, as functions of `x`, so that they can
+/// be referred to from other waveforms.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionTable {
+    functions: HashMap<String, Function>,
+    waveforms: HashSet<String>,
+}
+
+impl FunctionTable {
+    pub fn new() -> FunctionTable {
+        Default::default()
+    }
+
+    /// Add a function to the table. Names must be unique and must not
+    /// shadow a built-in function, constant or renderer input, and the
+    /// body may only use its own parameters and the renderer's inputs.
+    pub fn define(
+        &mut self,
+        name: String,
+        params: Vec<String>,
+        body: Expression,
+    ) -> FunctionResult<()> {
+        // A waveform named after an input would replace that input in
+        // every other waveform's equation
+        if function_arity(&name).is_some()
+            || constant(&name).is_some()
+            || Param::from_name(&name).is_some()
+            || self.functions.contains_key(&name)
+        {
+            return Err(FunctionError::Duplicate(name));
+        }
+        if let Some(param) = params.iter().find(|p| constant(p).is_some()) {
+            return Err(FunctionError::Constant(param.clone()));
+        }
+        let bound: Vec<&str> = params.iter().map(String::as_str).collect();
+        check_names(&body, &bound).map_err(FunctionError::Names)?;
+        let body = optimise(body);
+        self.functions.insert(name, Function { params, body });
+        Ok(())
+    }
+
+    /// Add a waveform to the table. Its equation may refer to any of the
+    /// `waveforms` by name, optionally passing a different value for
+    /// `x` as in `saw(x*2)`.
+    pub fn define_waveform(
+        &mut self,
+        name: String,
+        equation: Expression,
+        waveforms: &[&str],
+    ) -> FunctionResult<()> {
+        let body = resolve_waveforms(equation, waveforms);
+        // Names bound to waveforms are only unknown when shadowed, but
+        // are worth suggesting.
+        check_names(&body, waveforms).map_err(FunctionError::Names)?;
+        let params = vec![String::from(Param::X.name())];
+        self.define(name.clone(), params, body)?;
+        self.waveforms.insert(name);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.functions.get(name)
+    }
+
+    pub fn is_waveform(&self, name: &str) -> bool {
+        self.waveforms.contains(name)
+    }
+
+    /// The names of all of the waveforms in the table.
+    pub fn waveforms(&self) -> Vec<&str> {
+        self.waveforms.iter().map(String::as_str).collect()
+    }
+
+    /// Check that the bodies of all functions and waveforms only call
+    /// functions that exist, and that none of them refers to itself.
+    pub fn check(&self) -> EquationResult<()> {
+        let mut names: Vec<&String> = self.functions.keys().collect();
+        names.sort();
+        for name in &names {
+            self.check_calls(&self.functions[*name].body)
+                .map_err(|e| EquationError::new(self.describe(name), e))?;
+        }
+        for name in &names {
+            self.check_recursion(name, &mut vec![name])
+                .map_err(|e| EquationError::new(self.describe(name), e))?;
+        }
+        for name in names {
+            self.check_branches(&self.functions[name].body, false)
+                .map_err(|e| EquationError::new(self.describe(name), e))?;
+        }
+        Ok(())
+    }
+
+    /// How to refer to the function or waveform `name` in error messages.
+    fn describe(&self, name: &str) -> String {
+        if self.waveforms.contains(name) {
+            format!("waveform '{}'", name)
+        } else {
+            format!("function '{}'", name)
+        }
+    }
+
+    /// Check that every call to a user-defined function in the
+    /// expression names a known function with the correct number of
+    /// arguments. Calls to built-in functions are checked by the parser.
+    pub fn check_calls(&self, exp: &Expression) -> FunctionResult<()> {
+        let mut result = Ok(());
+        visit_calls(exp, &mut |name, args| {
+            if result.is_err() || function_arity(name).is_some() {
+                return;
+            }
+            result = match self.functions.get(name) {
+                Some(f) if f.params.len() != args.len() => Err(FunctionError::Arity(
+                    name.to_string(),
+                    f.params.len(),
+                    args.len(),
+                )),
+                Some(_) => Ok(()),
+                None => Err(FunctionError::Unknown(name.to_string())),
+            };
+        });
+        result
+    }
+
+    /// Check that nothing which records a value every sample, such as
+    /// `delay` or a waveform using `prev`, is called from a branch of a
+    /// conditional which may be skipped. `conditional` is true within
+    /// such a branch. The functions must already be checked for
+    /// recursion.
+    pub fn check_branches(&self, exp: &Expression, conditional: bool) -> FunctionResult<()> {
+        match exp {
+            Expression::Identifier(_) | Expression::Number(_) => Ok(()),
+            Expression::Prefix(_, arg) => self.check_branches(arg, conditional),
+            // The right hand side of `&&` and `||` isn't always evaluated
+            Expression::Infix(lhs, op, rhs) => {
+                self.check_branches(lhs, conditional)?;
+                let skipped = *op == InfixOp::And || *op == InfixOp::Or;
+                self.check_branches(rhs, conditional || skipped)
+            }
+            Expression::Call(name, args) => {
+                if conditional && self.records(name) {
+                    return Err(FunctionError::Conditional(name.to_string()));
+                }
+                args.iter()
+                    .try_for_each(|arg| self.check_branches(arg, conditional))
+            }
+            Expression::Conditional(cond, then, otherwise) => {
+                self.check_branches(cond, conditional)?;
+                self.check_branches(then, true)?;
+                self.check_branches(otherwise, true)
+            }
+            Expression::Let(_, value, body) => {
+                self.check_branches(value, conditional)?;
+                self.check_branches(body, conditional)
+            }
+        }
+    }
+
+    /// Whether calling `name` records a value for every sample, in a
+    /// delay line or in the history of a waveform using `prev`.
+    fn records(&self, name: &str) -> bool {
+        match self.functions.get(name) {
+            Some(f) => {
+                (self.is_waveform(name) && self.uses_prev(&f.body))
+                    || any_call(&f.body, |callee| self.records(callee))
+            }
+            None => name == "delay",
+        }
+    }
+
+    /// Whether an expression refers to the history of the waveform it
+    /// is part of, directly or through the functions it calls.
+    fn uses_prev(&self, exp: &Expression) -> bool {
+        any_call(exp, |callee| match self.functions.get(callee) {
+            Some(f) => !self.is_waveform(callee) && self.uses_prev(&f.body),
+            None => callee == "prev",
+        })
+    }
+
+    /// Depth-first search of the functions called by the last function
+    /// in `path`, failing if any of them is already on the path.
+    fn check_recursion<'a>(&'a self, name: &'a str, path: &mut Vec<&'a str>) -> FunctionResult<()> {
+        let mut callees = Vec::new();
+        visit_calls(&self.functions[name].body, &mut |callee, _| {
+            if self.functions.contains_key(callee) && !callees.contains(&callee) {
+                callees.push(callee);
+            }
+        });
+        for callee in callees {
+            if path.contains(&callee) {
+                path.push(callee);
+                return Err(FunctionError::Recursive(path.join(" -> ")));
+            }
+            path.push(callee);
+            self.check_recursion(callee, path)?;
+            path.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Whether `f` is true of the name of any function called in the
+/// expression.
+fn any_call<F: Fn(&str) -> bool>(exp: &Expression, f: F) -> bool {
+    let mut found = false;
+    visit_calls(exp, &mut |name, _| found = found || f(name));
+    found
+}
+
+/// Calls `f` with the name and arguments of every function call in
+/// the expression.
+fn visit_calls<'a, F: FnMut(&'a str, &'a [Expression])>(exp: &'a Expression, f: &mut F) {
+    match exp {
+        Expression::Identifier(_) | Expression::Number(_) => (),
+        Expression::Prefix(_, arg) => visit_calls(arg, f),
+        Expression::Infix(lhs, _, rhs) => {
+            visit_calls(lhs, f);
+            visit_calls(rhs, f);
+        }
+        Expression::Call(name, args) => {
+            f(name, args);
+            for arg in args {
+                visit_calls(arg, f);
+            }
+        }
+        Expression::Conditional(cond, then, otherwise) => {
+            visit_calls(cond, f);
+            visit_calls(then, f);
+            visit_calls(otherwise, f);
+        }
+        Expression::Let(_, value, body) => {
+            visit_calls(value, f);
+            visit_calls(body, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn x() -> Expression {
+        Expression::identifier(String::from("x"))
+    }
+
+    #[test]
+    fn waveforms_cannot_be_named_after_inputs() {
+        let mut table = FunctionTable::new();
+        for name in &["time", "x", "freq", "duration"] {
+            assert_eq!(
+                table.define_waveform(String::from(*name), x(), &[name, "w"]),
+                Err(FunctionError::Duplicate(String::from(*name)))
+            );
+        }
+    }
+
+    #[test]
+    fn functions_cannot_be_named_after_inputs() {
+        let mut table = FunctionTable::new();
+        assert_eq!(
+            table.define(String::from("freq"), vec![String::from("x")], x()),
+            Err(FunctionError::Duplicate(String::from("freq")))
+        );
+    }
+}
@@ -19,75 +19,99 @@ NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FO
 DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE. */
 
-use std::f32;
+pub use crate::error::{EquationError, EquationResult, EvalError, EvalResult};
 
-pub use crate::error::{EvalError, EvalResult};
+pub mod compile;
+use self::compile::Program;
+
+pub mod function;
+use self::function::FunctionTable;
+
+pub mod names;
+use self::names::{check_names, resolve_waveforms};
+
+pub mod noise;
+
+pub mod oscillator;
 
 pub mod parse;
-use self::parse::{parse_single, Expression, InfixOp, PrefixOp};
+use self::parse::{optimise, parse_single, Expression};
 
-use super::samplegen::{Params, SampleGen};
+use super::samplegen::{Param, Params, SampleGen};
 
 #[derive(Clone)]
 pub struct Waveform {
-    equation: Expression,
+    program: Program,
 }
 
 impl Waveform {
-    pub fn new(e: String) -> Waveform {
+    /// Compile the waveform called `name`, which must already be in the
+    /// function table (see `define_waveforms`).
+    pub fn new(name: &str, functions: &FunctionTable) -> Waveform {
+        let equation = &functions.get(name).unwrap().body;
         Waveform {
-            equation: parse_single(e).unwrap(),
+            program: Program::compile(equation, functions),
         }
     }
 
-    fn eval(&self, exp: &Expression, p: &Params) -> EvalResult<f32> {
-        match exp {
-            Expression::Prefix(op, arg) => {
-                let arg_num = self.eval(arg, &p)?;
-                let n = match op {
-                    PrefixOp::Negate => -(arg_num),
-                    PrefixOp::Abs => arg_num.abs(),
-                    PrefixOp::Sgn => arg_num.signum(),
-                    PrefixOp::Sin => (arg_num * f32::consts::PI).sin(),
-                    PrefixOp::Cos => (arg_num * f32::consts::PI).cos(),
-                    // _ => unreachable!()
-                };
-                Ok(n)
-            }
-            Expression::Infix(arg1, op, arg2) => {
-                let arg1_num = self.eval(arg1, &p)?;
-                let arg2_num = self.eval(arg2, &p)?;
-                let n = match op {
-                    InfixOp::Add => arg1_num + arg2_num,
-                    InfixOp::Sub => arg1_num - arg2_num,
-                    InfixOp::Mul => arg1_num * arg2_num,
-                    InfixOp::Div => arg1_num / arg2_num,
-                    InfixOp::Mod => arg1_num % arg2_num,
-                    // _ => unreachable!()
-                };
-                Ok(n)
-            }
-            Expression::Number(x) => Ok(*x),
-            Expression::Identifier(x) => {
-                if p.contains_key(x) {
-                    Ok(p[x])
-                } else {
-                    Err(EvalError::Incomplete)
-                }
-            }
-            _ => Err(EvalError::Unexpected),
+    /// Compile an equation which isn't a named waveform, such as an
+    /// instrument's velocity curve. Like a waveform, it can refer to any
+    /// function or waveform in the table. Each of the `aliases` is
+    /// another name for one of the inputs, as in `t` for `x`.
+    pub fn from_equation(
+        context: String,
+        equation: &str,
+        aliases: &[(&str, Param)],
+        functions: &FunctionTable,
+    ) -> EquationResult<Waveform> {
+        let mut exp = parse_single(equation)
+            .map_err(|e| EquationError::parse(context.clone(), equation, &e))?;
+        for (alias, param) in aliases {
+            let value = Expression::identifier(String::from(param.name()));
+            exp = Expression::let_binding(String::from(*alias), value, exp);
         }
+        let exp = resolve_waveforms(exp, &functions.waveforms());
+        check_names(&exp, &[]).map_err(|e| EquationError::new(context.clone(), e))?;
+        functions
+            .check_calls(&exp)
+            .and_then(|_| functions.check_branches(&exp, false))
+            .map_err(|e| EquationError::new(context, e))?;
+        Ok(Waveform {
+            program: Program::compile(&optimise(exp), functions),
+        })
     }
 }
 
+/// Parse the equations of the named waveforms and add them to the
+/// function table, so that each waveform can refer to the others.
+pub fn define_waveforms(
+    waveforms: &[(&str, &str)],
+    functions: &mut FunctionTable,
+) -> EquationResult<()> {
+    let names: Vec<&str> = waveforms.iter().map(|(name, _)| *name).collect();
+    for (name, e) in waveforms {
+        let context = format!("waveform '{}'", name);
+        let equation = match parse_single(e) {
+            Ok(equation) => equation,
+            Err(err) => return Err(EquationError::parse(context, e, &err)),
+        };
+        functions
+            .define_waveform(name.to_string(), equation, &names)
+            .map_err(|err| EquationError::new(context, err))?;
+    }
+    Ok(())
+}
+
 impl SampleGen for Waveform {
-    fn cache(&mut self, _p: &Params) {}
+    fn cache(&mut self, p: &Params) {
+        self.program.prepare(p);
+    }
 
-    fn get_sample(&self, p: &Params) -> Option<f32> {
-        self.eval(&self.equation, p).ok()
+    fn get_sample(&mut self, p: &Params) -> Option<f32> {
+        self.program.eval(p).ok()
     }
 
-    fn get_mod_sample(&self, p: &Params) -> Option<f32> {
-        Some((self.eval(&self.equation, p).unwrap() + 1.0) / 2.0)
+    fn get_mod_sample(&mut self, p: &Params) -> Option<f32> {
+        Some((self.program.eval(p).unwrap() + 1.0) / 2.0)
     }
 }
@@ -0,0 +1,132 @@
+pub use crate::error::{NameError, NameResult};
+
+use super::parse::Expression;
+use crate::samplegen::Param;
+
+/// Check that every identifier in the expression is either bound by an
+/// enclosing `let`, one of `bound`, or a parameter supplied by the
+/// renderer. All unknown names are reported together.
+pub fn check_names(exp: &Expression, bound: &[&str]) -> NameResult<()> {
+    let mut scope = bound.to_vec();
+    let mut unknown = Vec::new();
+    visit_names(exp, &mut scope, &mut |name, scope| {
+        if !unknown.iter().any(|(u, _)| u == name) {
+            unknown.push((name.to_string(), suggest(name, scope)));
+        }
+    });
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(NameError { unknown })
+    }
+}
+
+/// Replace references to the named waveforms with calls which pass on
+/// the current value of `x`, so that `square` means `square(x)`. Names
+/// bound by `let` take precedence over waveforms.
+pub fn resolve_waveforms(exp: Expression, waveforms: &[&str]) -> Expression {
+    resolve(exp, waveforms, &mut Vec::new())
+}
+
+fn resolve(exp: Expression, waveforms: &[&str], scope: &mut Vec<String>) -> Expression {
+    match exp {
+        Expression::Identifier(name) => {
+            if waveforms.contains(&name.as_str()) && !scope.contains(&name) {
+                let x = Expression::identifier(String::from(Param::X.name()));
+                Expression::call(name, vec![x])
+            } else {
+                Expression::Identifier(name)
+            }
+        }
+        Expression::Number(n) => Expression::Number(n),
+        Expression::Prefix(op, arg) => Expression::prefix(op, resolve(*arg, waveforms, scope)),
+        Expression::Infix(lhs, op, rhs) => Expression::infix(
+            resolve(*lhs, waveforms, scope),
+            op,
+            resolve(*rhs, waveforms, scope),
+        ),
+        Expression::Call(name, args) => Expression::call(
+            name,
+            args.into_iter()
+                .map(|arg| resolve(arg, waveforms, scope))
+                .collect(),
+        ),
+        Expression::Conditional(cond, then, otherwise) => Expression::conditional(
+            resolve(*cond, waveforms, scope),
+            resolve(*then, waveforms, scope),
+            resolve(*otherwise, waveforms, scope),
+        ),
+        Expression::Let(name, value, body) => {
+            let value = resolve(*value, waveforms, scope);
+            scope.push(name);
+            let body = resolve(*body, waveforms, scope);
+            let name = scope.pop().unwrap();
+            Expression::let_binding(name, value, body)
+        }
+    }
+}
+
+/// Calls `f` with every identifier which isn't in scope where it is used.
+fn visit_names<'a, F>(exp: &'a Expression, scope: &mut Vec<&'a str>, f: &mut F)
+where
+    F: FnMut(&'a str, &[&'a str]),
+{
+    match exp {
+        Expression::Identifier(name) => {
+            if !scope.contains(&name.as_str()) && Param::from_name(name).is_none() {
+                f(name, scope);
+            }
+        }
+        Expression::Number(_) => (),
+        Expression::Prefix(_, arg) => visit_names(arg, scope, f),
+        Expression::Infix(lhs, _, rhs) => {
+            visit_names(lhs, scope, f);
+            visit_names(rhs, scope, f);
+        }
+        Expression::Call(_, args) => {
+            for arg in args {
+                visit_names(arg, scope, f);
+            }
+        }
+        Expression::Conditional(cond, then, otherwise) => {
+            visit_names(cond, scope, f);
+            visit_names(then, scope, f);
+            visit_names(otherwise, scope, f);
+        }
+        Expression::Let(name, value, body) => {
+            visit_names(value, scope, f);
+            scope.push(name);
+            visit_names(body, scope, f);
+            scope.pop();
+        }
+    }
+}
+
+/// The known name closest to `name`, if it is only a typo or two away.
+fn suggest(name: &str, scope: &[&str]) -> Option<String> {
+    let params = Param::ALL.iter().map(|p| p.name());
+    scope
+        .iter()
+        .copied()
+        .chain(params)
+        .map(|known| (edit_distance(name, known), known))
+        .filter(|&(distance, known)| distance <= 2 && distance < known.chars().count())
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, known)| known.to_string())
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
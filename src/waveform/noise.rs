@@ -0,0 +1,107 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Random signals, called like built-in functions.
+///
+/// Every call to `white()`, `pink()` or `brown()` in an equation has its
+/// own generator, seeded at the start of each note, so that renders are
+/// reproducible. `noise(t)` is smooth value noise, which only depends on
+/// `t` and the note's seed.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Noise {
+    White,
+    Pink,
+    Brown,
+    Value,
+}
+
+impl Noise {
+    pub fn from_name(name: &str) -> Option<Noise> {
+        match name {
+            "white" => Some(Noise::White),
+            "pink" => Some(Noise::Pink),
+            "brown" => Some(Noise::Brown),
+            "noise" => Some(Noise::Value),
+            _ => None,
+        }
+    }
+
+    /// Whether each call needs a generator which changes from sample
+    /// to sample.
+    pub fn is_stateful(self) -> bool {
+        self != Noise::Value
+    }
+}
+
+/// The state of a single call to `white()`, `pink()` or `brown()`.
+#[derive(Clone, Debug)]
+pub struct Generator {
+    rng: StdRng,
+    pink: [f32; 7],
+    brown: f32,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        Generator {
+            rng: StdRng::seed_from_u64(seed),
+            pink: [0.0; 7],
+            brown: 0.0,
+        }
+    }
+
+    /// The next sample of the given kind of noise, roughly between -1 and 1.
+    pub fn next(&mut self, kind: Noise) -> f32 {
+        let white = self.rng.gen_range(-1.0, 1.0);
+        match kind {
+            Noise::White | Noise::Value => white,
+            // Paul Kellet's filter, which is accurate to within 0.05dB
+            // above 9.2Hz at 44.1kHz
+            Noise::Pink => {
+                let b = &mut self.pink;
+                b[0] = 0.99886 * b[0] + white * 0.055_517_9;
+                b[1] = 0.99332 * b[1] + white * 0.075_075_9;
+                b[2] = 0.96900 * b[2] + white * 0.153_852;
+                b[3] = 0.86650 * b[3] + white * 0.310_485_6;
+                b[4] = 0.55000 * b[4] + white * 0.532_952_2;
+                b[5] = -0.7616 * b[5] - white * 0.016_898;
+                let pink = b.iter().sum::<f32>() + white * 0.5362;
+                b[6] = white * 0.115_926;
+                pink * 0.11
+            }
+            // Leaky integration of white noise
+            Noise::Brown => {
+                self.brown = (self.brown + 0.02 * white) / 1.02;
+                self.brown * 3.5
+            }
+        }
+    }
+}
+
+/// Value noise: random values between -1 and 1 at whole numbers,
+/// smoothly interpolated in between.
+pub fn value_noise(seed: u64, t: f32) -> f32 {
+    let i = t.floor();
+    let f = t - i;
+    let a = lattice(seed, i as i64);
+    let b = lattice(seed, i as i64 + 1);
+    a + (b - a) * f * f * (3.0 - 2.0 * f)
+}
+
+fn lattice(seed: u64, i: i64) -> f32 {
+    let h = hash(seed, i as u64) >> 40;
+    h as f32 / (1 << 23) as f32 - 1.0
+}
+
+/// Combine two values into a well-distributed 64-bit hash.
+pub fn hash(a: u64, b: u64) -> u64 {
+    mix(a ^ mix(b))
+}
+
+/// The SplitMix64 finaliser.
+fn mix(z: u64) -> u64 {
+    let z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
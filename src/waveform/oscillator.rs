@@ -0,0 +1,106 @@
+use crate::samplegen::{Param, Params};
+
+/// Band-limited oscillators, called like built-in functions.
+///
+/// Their phase argument has a period of 2, like `x`. Discontinuities in
+/// the naive waveforms are smoothed with polynomial corrections (PolyBLEP
+/// for steps and PolyBLAMP for corners) over the sample on either side.
+/// How wide the corrections are depends on how far the phase argument
+/// advances each sample, so each call remembers its previous argument and
+/// scaled phases such as `blsaw(2*x)` are smoothed correctly. This assumes
+/// the call is evaluated every sample.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Oscillator {
+    /// `blsaw(x)`: rises from -1 to 1, like `x%2-1`.
+    Saw,
+    /// `blsquare(x, width)`: 1 for the first `width` of each cycle and -1
+    /// for the rest, like `sgn(sin(x))` when `width` is 0.5.
+    Square,
+    /// `bltri(x)`: starts at 0 and peaks a quarter of the way through
+    /// each cycle, like `sin(x)`.
+    Triangle,
+}
+
+impl Oscillator {
+    pub fn from_name(name: &str) -> Option<Oscillator> {
+        match name {
+            "blsaw" => Some(Oscillator::Saw),
+            "blsquare" => Some(Oscillator::Square),
+            "bltri" => Some(Oscillator::Triangle),
+            _ => None,
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Oscillator::Saw | Oscillator::Triangle => 1,
+            Oscillator::Square => 2,
+        }
+    }
+
+    /// The fraction of a cycle the phase argument advanced by since
+    /// `last`, its value at the previous sample. For the first sample of a
+    /// note it is assumed to advance at the carrier's frequency, as `x`
+    /// does.
+    pub fn step(phase: f32, last: Option<f32>, p: &Params) -> f32 {
+        match last {
+            // The phase may run backwards, or wrap around as in `x%2`
+            Some(last) => {
+                let d = fract((phase - last) / 2.0);
+                d.min(1.0 - d)
+            }
+            None => (p[Param::CarrierFrequency] / p[Param::Rate] / 2.0).min(0.5),
+        }
+    }
+
+    /// The oscillator's value for the arguments `a`, where `dt` is the
+    /// fraction of a cycle the phase advances by each sample.
+    pub fn apply(self, a: &[f32], dt: f32) -> f32 {
+        let t = fract(a[0] / 2.0);
+        match self {
+            Oscillator::Saw => 2.0 * t - 1.0 - poly_blep(t, dt),
+            Oscillator::Square => {
+                let width = a[1].clamp(0.0, 1.0);
+                let naive = if t < width { 1.0 } else { -1.0 };
+                naive + poly_blep(t, dt) - poly_blep(fract(t - width), dt)
+            }
+            Oscillator::Triangle => {
+                let naive = 4.0 * (fract(t + 0.75) - 0.5).abs() - 1.0;
+                naive
+                    - 4.0 * dt * (poly_blamp(fract(t - 0.25), dt) - poly_blamp(fract(t - 0.75), dt))
+            }
+        }
+    }
+}
+
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+/// Correction for a rising step of 2 at phase 0, where `t` is the phase
+/// within the cycle and `dt` is the phase increment per sample.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// Correction for the corner at phase 0 where the slope increases by 2
+/// per sample. This is the integral of `poly_blep`.
+fn poly_blamp(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt - 1.0;
+        -t * t * t / 3.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt + 1.0;
+        t * t * t / 3.0
+    } else {
+        0.0
+    }
+}
@@ -20,8 +20,12 @@ DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE. */
 
 pub use crate::error::{ParseError, ParseResult};
+use std::f32::consts;
+use std::fmt;
 use std::iter::Peekable;
 
+use super::compile::call_builtin;
+
 /// Represents an AST prefix operator.
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum PrefixOp {
@@ -30,6 +34,21 @@ pub enum PrefixOp {
     Sgn,
     Sin,
     Cos,
+    Tan,
+    RawSin,
+    RawCos,
+    RawTan,
+    Tanh,
+    Exp,
+    Ln,
+    Log2,
+    Log10,
+    Sqrt,
+    Floor,
+    Ceil,
+    Round,
+    Fract,
+    Not,
 }
 
 /// Represents an AST infix operator
@@ -40,6 +59,15 @@ pub enum InfixOp {
     Mul,
     Div,
     Mod,
+    Pow,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or,
 }
 
 /// Represents an AST expression.
@@ -50,6 +78,64 @@ pub enum Expression {
     Number(f32),
     Prefix(PrefixOp, Box<Expression>),
     Infix(Box<Expression>, InfixOp, Box<Expression>),
+    Call(String, Vec<Expression>),
+    Conditional(Box<Expression>, Box<Expression>, Box<Expression>),
+    Let(String, Box<Expression>, Box<Expression>),
+}
+
+/// Built-in functions which are called with a bracketed argument
+/// list, along with the number of arguments each one expects.
+const FUNCTIONS: &[(&str, usize)] = &[
+    ("min", 2),
+    ("max", 2),
+    ("clamp", 3),
+    ("pow", 2),
+    ("atan2", 2),
+    ("ratan2", 2),
+    ("lerp", 3),
+    ("blsaw", 1),
+    ("blsquare", 2),
+    ("bltri", 1),
+    ("white", 0),
+    ("pink", 0),
+    ("brown", 0),
+    ("noise", 1),
+    ("prev", 1),
+    ("delay", 2),
+    ("harmonics", 2),
+    ("table", 2),
+    ("step", 2),
+];
+
+/// Built-in functions whose last argument is an array literal, such as
+/// `table(pos, [0, 0.5, 1])`. The array's values are passed to them as
+/// extra arguments.
+const ARRAY_FUNCTIONS: &[&str] = &["harmonics", "table", "step"];
+
+/// Named constants, which can't be rebound by `let` or used as the
+/// name of a parameter.
+const CONSTANTS: &[(&str, f32)] = &[
+    ("pi", consts::PI),
+    ("tau", 2.0 * consts::PI),
+    ("e", consts::E),
+];
+
+/// Look up the value of the named constant `name`, or `None` if there
+/// is no such constant.
+pub fn constant(name: &str) -> Option<f32> {
+    CONSTANTS
+        .iter()
+        .find(|(c, _)| *c == name)
+        .map(|(_, value)| *value)
+}
+
+/// Look up the number of arguments taken by the built-in function
+/// `name`, or `None` if there is no such function.
+pub fn function_arity(name: &str) -> Option<usize> {
+    FUNCTIONS
+        .iter()
+        .find(|(f, _)| *f == name)
+        .map(|(_, arity)| *arity)
 }
 
 impl Expression {
@@ -85,6 +171,124 @@ impl Expression {
     pub fn infix(lhs: Expression, op: InfixOp, rhs: Expression) -> Self {
         Expression::Infix(Box::new(lhs), op, Box::new(rhs))
     }
+
+    /// # New Function Call Expression
+    ///
+    /// Represents a call to a named function with a list of argument
+    /// expressions.
+    pub fn call(name: String, args: Vec<Expression>) -> Self {
+        Expression::Call(name, args)
+    }
+
+    /// # New Conditional Expression
+    ///
+    /// Evaluates to one of two expressions depending on whether the
+    /// condition is non-zero.
+    pub fn conditional(cond: Expression, then: Expression, otherwise: Expression) -> Self {
+        Expression::Conditional(Box::new(cond), Box::new(then), Box::new(otherwise))
+    }
+
+    /// # New Let Binding Expression
+    ///
+    /// Binds the value of an expression to a local name, which can be
+    /// referred to as an identifier within the body expression.
+    pub fn let_binding(name: String, value: Expression, body: Expression) -> Self {
+        Expression::Let(name, Box::new(value), Box::new(body))
+    }
+}
+
+/// The units which numeric literals can be suffixed with.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Unit {
+    Hertz,
+    Milliseconds,
+    Seconds,
+    Decibels,
+    Semitones,
+    Cents,
+}
+
+impl Unit {
+    /// Look up a unit by its suffix, ignoring case.
+    pub fn from_suffix(suffix: &str) -> Option<Unit> {
+        match &*suffix.to_lowercase() {
+            "hz" => Some(Unit::Hertz),
+            "ms" => Some(Unit::Milliseconds),
+            "s" => Some(Unit::Seconds),
+            "db" => Some(Unit::Decibels),
+            "st" => Some(Unit::Semitones),
+            "ct" => Some(Unit::Cents),
+            _ => None,
+        }
+    }
+
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Unit::Hertz => "hz",
+            Unit::Milliseconds => "ms",
+            Unit::Seconds => "s",
+            Unit::Decibels => "db",
+            Unit::Semitones => "st",
+            Unit::Cents => "ct",
+        }
+    }
+
+    /// Convert a quantity in this unit to the units used by equations.
+    /// Times are in seconds, frequencies are in the units of angle used
+    /// by `sin` per second (so `sin(time*5hz)` has a frequency of 5Hz),
+    /// and decibels, semitones and cents are ratios.
+    pub fn normalise(self, n: f32) -> f32 {
+        match self {
+            Unit::Hertz => n * 2.0,
+            Unit::Milliseconds => n / 1000.0,
+            Unit::Seconds => n,
+            Unit::Decibels => 10f32.powf(n / 20.0),
+            Unit::Semitones => 2f32.powf(n / 12.0),
+            Unit::Cents => 2f32.powf(n / 1200.0),
+        }
+    }
+
+    /// Whether the unit measures a ratio, so that negating a quantity
+    /// gives its reciprocal.
+    pub fn is_logarithmic(self) -> bool {
+        match self {
+            Unit::Decibels | Unit::Semitones | Unit::Cents => true,
+            Unit::Hertz | Unit::Milliseconds | Unit::Seconds => false,
+        }
+    }
+
+    pub fn is_time(self) -> bool {
+        self == Unit::Milliseconds || self == Unit::Seconds
+    }
+}
+
+/// Parse a Quantity
+///
+/// Parses a number with an optional sign and unit, such as `-6db` or
+/// `20ms`, as accepted for numbers in the arrangement file. Returns the
+/// normalised value and its unit.
+pub fn parse_quantity(s: &str) -> ParseResult<(f32, Option<Unit>)> {
+    let t = Tokeniser::new_from_str(s);
+    let mut p = Parser::new(t);
+    let sign = match p.peek_token() {
+        Some(Token::Plus) => Some(1.0),
+        Some(Token::Minus) => Some(-1.0),
+        _ => None,
+    };
+    if sign.is_some() {
+        p.next_token();
+    }
+    let sign = sign.unwrap_or(1.0);
+    let quantity = match p.next_token() {
+        Some(Token::Number(n)) => (sign * n, None),
+        Some(Token::Quantity(n, unit)) => (unit.normalise(sign * n), Some(unit)),
+        Some(token) => return Err(p.unexpected_token(&token, "a number")),
+        None => return Err(p.unexpected("a number")),
+    };
+    if p.peek_token().is_some() {
+        return Err(p.unexpected("end of input"));
+    }
+    Ok(quantity)
 }
 
 /// Parse a Single Expression
@@ -94,7 +298,102 @@ impl Expression {
 pub fn parse_single<S: AsRef<str>>(s: S) -> ParseResult<Expression> {
     let t = Tokeniser::new_from_str(s.as_ref());
     let mut p = Parser::new(t);
-    p.single_expression()
+    let expr = p.single_expression()?;
+    if p.lexer.peek().is_some() {
+        return Err(p.unexpected("end of input"));
+    }
+    Ok(expr)
+}
+
+/// Optimise an Expression
+///
+/// Folds constant subexpressions, including `let` bindings of
+/// constants and conditionals with a constant condition, and removes
+/// identities such as `x*1`, `x+0` and `--x`.
+pub fn optimise(exp: Expression) -> Expression {
+    use self::Expression::Number;
+
+    match exp {
+        Expression::Prefix(op, arg) => match (op, optimise(*arg)) {
+            (op, Number(n)) => Number(op.apply(n)),
+            (PrefixOp::Negate, Expression::Prefix(PrefixOp::Negate, inner)) => *inner,
+            (op, arg) => Expression::prefix(op, arg),
+        },
+        Expression::Infix(lhs, op, rhs) => match (optimise(*lhs), op, optimise(*rhs)) {
+            (Number(a), op, Number(b)) => Number(op.apply(a, b)),
+            (x, InfixOp::Add, Number(z)) | (Number(z), InfixOp::Add, x) if z == 0.0 => x,
+            (x, InfixOp::Sub, Number(0.0)) => x,
+            (Number(0.0), InfixOp::Sub, x) => optimise(Expression::prefix(PrefixOp::Negate, x)),
+            (x, InfixOp::Mul, Number(o)) | (Number(o), InfixOp::Mul, x) if o == 1.0 => x,
+            (x, InfixOp::Div, Number(o)) | (x, InfixOp::Pow, Number(o)) if o == 1.0 => x,
+            (lhs, op, rhs) => Expression::infix(lhs, op, rhs),
+        },
+        Expression::Call(name, args) => {
+            let args: Vec<Expression> = args.into_iter().map(optimise).collect();
+            let values: Vec<f32> = args
+                .iter()
+                .filter_map(|arg| match arg {
+                    Number(n) => Some(*n),
+                    _ => None,
+                })
+                .collect();
+            // Only fold calls whose arguments are all constant
+            let folded = if values.len() == args.len() {
+                call_builtin(&name, &values)
+            } else {
+                None
+            };
+            match folded {
+                Some(n) => Number(n),
+                None => Expression::call(name, args),
+            }
+        }
+        Expression::Conditional(cond, then, otherwise) => match optimise(*cond) {
+            Number(n) if n != 0.0 => optimise(*then),
+            Number(_) => optimise(*otherwise),
+            cond => Expression::conditional(cond, optimise(*then), optimise(*otherwise)),
+        },
+        Expression::Let(name, value, body) => match optimise(*value) {
+            Number(n) => optimise(substitute(*body, &name, n)),
+            value => Expression::let_binding(name, value, optimise(*body)),
+        },
+        exp => exp,
+    }
+}
+
+/// Replace references to the local `name` in an expression with a
+/// constant value.
+fn substitute(exp: Expression, name: &str, n: f32) -> Expression {
+    match exp {
+        Expression::Identifier(ref s) if s == name => Expression::Number(n),
+        Expression::Prefix(op, arg) => Expression::prefix(op, substitute(*arg, name, n)),
+        Expression::Infix(lhs, op, rhs) => {
+            Expression::infix(substitute(*lhs, name, n), op, substitute(*rhs, name, n))
+        }
+        Expression::Call(f, args) => {
+            let args = args
+                .into_iter()
+                .map(|arg| substitute(arg, name, n))
+                .collect();
+            Expression::call(f, args)
+        }
+        Expression::Conditional(cond, then, otherwise) => Expression::conditional(
+            substitute(*cond, name, n),
+            substitute(*then, name, n),
+            substitute(*otherwise, name, n),
+        ),
+        Expression::Let(local, value, body) => {
+            let value = substitute(*value, name, n);
+            // An inner binding of the same name shadows this one
+            let body = if local == name {
+                *body
+            } else {
+                substitute(*body, name, n)
+            };
+            Expression::let_binding(local, value, body)
+        }
+        exp => exp,
+    }
 }
 
 /// This structure represents a single token from the input source
@@ -102,12 +401,18 @@ pub fn parse_single<S: AsRef<str>>(s: S) -> ParseResult<Expression> {
 #[derive(Debug, PartialEq)]
 pub enum Token<'a> {
     /// Represents a string of alphabetic characters. This could be a
-    /// language keyword or a variable or type identifier.
+    /// language keyword or a variable or type identifier. Named
+    /// constants such as `pi` are also `Word`s, and are replaced by
+    /// their values when the parser reaches them.
     Word(&'a str),
     /// Whitespace trivia
     Whitespace(&'a str),
     /// Constant numerical value.
     Number(f32),
+    /// A numeric literal with a unit, such as `20ms`.
+    Quantity(f32, Unit),
+    /// A numeric literal which isn't a valid number, such as `1.2.3`.
+    BadNumber(&'a str),
     /// The `+` character
     Plus,
     /// The `-` character
@@ -118,14 +423,87 @@ pub enum Token<'a> {
     Slash,
     /// The `(` character
     Percent,
+    /// The `^` character
+    Caret,
     /// The `%` character
     OpenBracket,
     /// The `)` character
     CloseBracket,
+    /// The `[` character
+    OpenSquare,
+    /// The `]` character
+    CloseSquare,
+    /// The `,` character
+    Comma,
+    /// The `<` character
+    Less,
+    /// The `<=` operator
+    LessEqual,
+    /// The `>` character
+    Greater,
+    /// The `>=` operator
+    GreaterEqual,
+    /// The `==` operator
+    EqualEqual,
+    /// The `!=` operator
+    BangEqual,
+    /// The `!` character
+    Bang,
+    /// The `&&` operator
+    AmpAmp,
+    /// The `||` operator
+    PipePipe,
+    /// The `?` character
+    Question,
+    /// The `:` character
+    Colon,
+    /// The `;` character
+    Semicolon,
+    /// The `=` character
+    Equals,
     /// An unrecognised token
     Unknown(char),
 }
 
+impl<'a> fmt::Display for Token<'a> {
+    /// Describes the token as it would appear in an error message.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match *self {
+            Token::Word(word) => return write!(f, "'{}'", word),
+            Token::Whitespace(_) => return write!(f, "whitespace"),
+            Token::Number(n) => return write!(f, "'{}'", n),
+            Token::Quantity(n, unit) => return write!(f, "'{}{}'", n, unit.suffix()),
+            Token::BadNumber(s) => return write!(f, "malformed number '{}'", s),
+            Token::Unknown(c) => return write!(f, "unrecognised character '{}'", c),
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::Percent => "%",
+            Token::Caret => "^",
+            Token::OpenBracket => "(",
+            Token::CloseBracket => ")",
+            Token::OpenSquare => "[",
+            Token::CloseSquare => "]",
+            Token::Comma => ",",
+            Token::Less => "<",
+            Token::LessEqual => "<=",
+            Token::Greater => ">",
+            Token::GreaterEqual => ">=",
+            Token::EqualEqual => "==",
+            Token::BangEqual => "!=",
+            Token::Bang => "!",
+            Token::AmpAmp => "&&",
+            Token::PipePipe => "||",
+            Token::Question => "?",
+            Token::Colon => ":",
+            Token::Semicolon => ";",
+            Token::Equals => "=",
+        };
+        write!(f, "'{}'", symbol)
+    }
+}
+
 /// Tokeniser
 ///
 /// An object which can run a regex state machine over an input
@@ -137,7 +515,7 @@ struct Tokeniser<'a> {
 
 impl<'a> Tokeniser<'a> {
     /// Creates a new tokeniser from the given string slice.
-    pub fn new_from_str(source: &'a str) -> Tokeniser {
+    pub fn new_from_str(source: &'a str) -> Tokeniser<'a> {
         Tokeniser {
             buff: source,
             idx: 0,
@@ -151,44 +529,86 @@ impl<'a> Tokeniser<'a> {
         let ts = self.idx;
         let mut te = ts;
         let mut chars = self.buff[ts..].chars();
-        let tok = chars.next().and_then(|c| {
+        let tok = chars.next().map(|c| {
             te += c.len_utf8();
             match c {
-                '+' => Some(Token::Plus),
-                '-' => Some(Token::Minus),
-                '*' => Some(Token::Star),
-                '/' => Some(Token::Slash),
-                '%' => Some(Token::Percent),
-                '(' => Some(Token::OpenBracket),
-                ')' => Some(Token::CloseBracket),
+                '+' => Token::Plus,
+                '-' => Token::Minus,
+                '*' => Token::Star,
+                '/' => Token::Slash,
+                '%' => Token::Percent,
+                '^' => Token::Caret,
+                '(' => Token::OpenBracket,
+                ')' => Token::CloseBracket,
+                ',' => Token::Comma,
+                '[' => Token::OpenSquare,
+                ']' => Token::CloseSquare,
+                '?' => Token::Question,
+                ':' => Token::Colon,
+                ';' => Token::Semicolon,
+                '<' | '>' | '=' | '!' | '&' | '|' => {
+                    let two_char = match self.buff[ts..].get(..2) {
+                        Some("<=") => Some(Token::LessEqual),
+                        Some(">=") => Some(Token::GreaterEqual),
+                        Some("==") => Some(Token::EqualEqual),
+                        Some("!=") => Some(Token::BangEqual),
+                        Some("&&") => Some(Token::AmpAmp),
+                        Some("||") => Some(Token::PipePipe),
+                        _ => None,
+                    };
+                    match two_char {
+                        Some(tok) => {
+                            te += 1;
+                            tok
+                        }
+                        None => match c {
+                            '<' => Token::Less,
+                            '>' => Token::Greater,
+                            '!' => Token::Bang,
+                            '=' => Token::Equals,
+                            _ => Token::Unknown(c),
+                        },
+                    }
+                }
                 '#' => {
                     te += chars
                         .take_while(|c| *c != '\n')
                         .fold(0, |l, c| l + c.len_utf8());
-                    Some(Token::Whitespace(&self.buff[ts..te]))
+                    Token::Whitespace(&self.buff[ts..te])
                 }
-                '0'...'9' => {
+                '0'..='9' => {
                     te += chars
                         .take_while(|c| (*c >= '0' && *c <= '9') || (*c == '.'))
                         .count();
+                    let number_end = te;
+                    te += self.buff[te..]
+                        .chars()
+                        .take_while(|c| c.is_alphanumeric() || *c == '_')
+                        .fold(0, |l, c| l + c.len_utf8());
                     let token_str = &self.buff[ts..te];
-                    // we have cheked that it's a valid numeric literal,
-                    // so unwrap is fine here.
-                    Some(Token::Number(token_str.parse::<f32>().unwrap()))
+                    let unit = &self.buff[number_end..te];
+                    match (
+                        token_str[..number_end - ts].parse::<f32>(),
+                        Unit::from_suffix(unit),
+                    ) {
+                        (Ok(n), None) if unit.is_empty() => Token::Number(n),
+                        (Ok(n), Some(unit)) => Token::Quantity(n, unit),
+                        _ => Token::BadNumber(token_str),
+                    }
                 }
                 c if c.is_alphabetic() || c == '_' => {
                     te += chars
                         .take_while(|c| c.is_alphanumeric() || *c == '_')
                         .fold(0, |l, c| l + c.len_utf8());
-                    Some(Token::Word(&self.buff[ts..te]))
+                    Token::Word(&self.buff[ts..te])
                 }
                 c if c.is_whitespace() => {
                     te += chars
                         .take_while(|c| c.is_whitespace())
                         .fold(0, |l, c| l + c.len_utf8());
-                    Some(Token::Whitespace(&self.buff[ts..te]))
+                    Token::Whitespace(&self.buff[ts..te])
                 }
-                _ => Some(Token::Unknown(c)),
+                _ => Token::Unknown(c),
             }
         });
         self.idx = te;
@@ -203,18 +623,19 @@ impl<'a> Tokeniser<'a> {
 /// tokeniser too. This means the `Tokeniser` doesn't have to worry
 /// about skipping certain lexemes in the grammar.
 impl<'a> Iterator for Tokeniser<'a> {
-    type Item = Token<'a>;
+    type Item = (usize, Token<'a>);
 
     /// Iterator next method. This method returns the next
-    /// non-whitespace token in the `Tokeniser`'s stream of `Token`s.
+    /// non-whitespace token in the `Tokeniser`'s stream of `Token`s,
+    /// along with the byte offset it starts at.
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(tok) = self.next_raw() {
-            if let Token::Whitespace(_) = tok {
-            } else {
-                return Some(tok);
+        loop {
+            let start = self.idx;
+            match self.next_raw()? {
+                Token::Whitespace(_) => (),
+                tok => return Some((start, tok)),
             }
         }
-        None
     }
 }
 
@@ -222,28 +643,69 @@ impl<'a> Iterator for Tokeniser<'a> {
 /// an expression tree, or a parse error.
 struct Parser<'a> {
     lexer: Peekable<Tokeniser<'a>>,
+    /// Byte offset of the most recently consumed token.
+    position: usize,
+    /// Byte offset of the end of the input.
+    end: usize,
 }
 
 impl<'a> Parser<'a> {
     /// Create a new Parser from a given token stream.
     pub fn new(t: Tokeniser<'a>) -> Self {
         Parser {
+            end: t.buff.len(),
             lexer: t.peekable(),
+            position: 0,
         }
     }
 
+    /// Consume the next token, remembering where it started.
+    fn next_token(&mut self) -> Option<Token<'a>> {
+        let (position, token) = self.lexer.next()?;
+        self.position = position;
+        Some(token)
+    }
+
+    /// Look at the next token without consuming it.
+    fn peek_token(&mut self) -> Option<&Token<'a>> {
+        self.lexer.peek().map(|(_, token)| token)
+    }
+
     /// Moves the token stream on by a single token, if the
     /// token's lexeme is of the given type.
     pub fn expect(&mut self, expected: Token) -> ParseResult<()> {
+        if self.peek_token() == Some(&expected) {
+            self.next_token();
+            Ok(())
+        } else {
+            Err(self.unexpected(&expected.to_string()))
+        }
+    }
+
+    /// Error for when the next token isn't what was expected.
+    fn unexpected(&mut self, expected: &str) -> ParseError {
+        let end = self.end;
         match self.lexer.peek() {
-            Some(token) if token == &expected => Ok(()),
-            Some(_) => Err(ParseError::Unexpected),
-            None => Err(ParseError::Incomplete),
+            Some((position, token)) => ParseError::Unexpected {
+                position: *position,
+                expected: String::from(expected),
+                found: token.to_string(),
+            },
+            None => ParseError::Incomplete {
+                position: end,
+                expected: String::from(expected),
+            },
+        }
+    }
+
+    /// Error for when the most recently consumed token wasn't what
+    /// was expected.
+    fn unexpected_token(&self, token: &Token, expected: &str) -> ParseError {
+        ParseError::Unexpected {
+            position: self.position,
+            expected: String::from(expected),
+            found: token.to_string(),
         }
-        .map(|ok| {
-            self.lexer.next();
-            ok
-        })
     }
 
     // /// Attempt to parse an identifier
@@ -273,31 +735,150 @@ impl<'a> Parser<'a> {
         self.expression(0)
     }
 
+    /// Returns true if the next token is a quantity in decibels,
+    /// semitones or cents.
+    fn next_is_logarithmic(&mut self) -> bool {
+        match self.peek_token() {
+            Some(Token::Quantity(_, unit)) => unit.is_logarithmic(),
+            _ => false,
+        }
+    }
+
     /// Returns true if the next token's lbp is > the given rbp
     fn next_binds_tighter_than(&mut self, rbp: u32) -> bool {
-        self.lexer.peek().map_or(false, |t| t.lbp() > rbp)
+        self.peek_token().is_some_and(|t| t.lbp() > rbp)
     }
 
     /// Prefix Operator
     ///
     /// Parses the trailing expression for a prefix operator.
     fn prefix_op(&mut self, op: PrefixOp) -> ParseResult<Expression> {
-        let rhs = self.expression(100)?;
+        self.prefix_op_with_bp(op, 100)
+    }
+
+    /// Prefix Operator With Binding Power
+    ///
+    /// Parses the trailing expression for a prefix operator which
+    /// binds less tightly than the default.
+    fn prefix_op_with_bp(&mut self, op: PrefixOp, rbp: u32) -> ParseResult<Expression> {
+        let rhs = self.expression(rbp)?;
         Ok(Expression::prefix(op, rhs))
     }
 
+    /// Function Call
+    ///
+    /// Parses the arguments of a call to the named function.
+    fn call(&mut self, name: &str, arity: usize) -> ParseResult<Expression> {
+        let args = self.arguments(name, arity)?;
+        Ok(Expression::call(String::from(name), args))
+    }
+
+    /// Array Call
+    ///
+    /// Parses a call to one of the `ARRAY_FUNCTIONS`, flattening the
+    /// array into the argument list.
+    fn array_call(&mut self, name: &str) -> ParseResult<Expression> {
+        self.expect(Token::OpenBracket)?;
+        let mut args = vec![self.single_expression()?];
+        self.expect(Token::Comma)?;
+        args.extend(self.list(Token::OpenSquare, Token::CloseSquare)?);
+        self.expect(Token::CloseBracket)?;
+        Ok(Expression::call(String::from(name), args))
+    }
+
+    /// Conditional Call
+    ///
+    /// Parses the condition and branches of an `if(cond, then, else)`
+    /// expression.
+    fn if_call(&mut self) -> ParseResult<Expression> {
+        let mut args = self.arguments("if", 3)?.into_iter();
+        let cond = args.next().unwrap();
+        let then = args.next().unwrap();
+        let otherwise = args.next().unwrap();
+        Ok(Expression::conditional(cond, then, otherwise))
+    }
+
+    /// Let Binding
+    ///
+    /// Parses the `name = value;` of a `let` expression followed by the
+    /// body in which the name is bound.
+    fn let_binding(&mut self) -> ParseResult<Expression> {
+        let name = match self.peek_token() {
+            Some(Token::Word(name)) => String::from(*name),
+            _ => return Err(self.unexpected("a name")),
+        };
+        self.next_token();
+        if constant(&name).is_some() {
+            return Err(ParseError::Constant {
+                position: self.position,
+                name,
+            });
+        }
+        self.expect(Token::Equals)?;
+        let value = self.single_expression()?;
+        self.expect(Token::Semicolon)?;
+        let body = self.single_expression()?;
+        Ok(Expression::let_binding(name, value, body))
+    }
+
+    /// Argument List
+    ///
+    /// Parses a bracketed, comma-separated argument list, checking
+    /// that the number of arguments matches the function's arity.
+    fn arguments(&mut self, name: &str, arity: usize) -> ParseResult<Vec<Expression>> {
+        let position = self.position;
+        let args = self.argument_list()?;
+        if args.len() != arity {
+            return Err(ParseError::Arity {
+                position,
+                name: String::from(name),
+                arity,
+                given: args.len(),
+            });
+        }
+        Ok(args)
+    }
+
+    /// Unchecked Argument List
+    ///
+    /// Parses a bracketed, comma-separated argument list of any length.
+    fn argument_list(&mut self) -> ParseResult<Vec<Expression>> {
+        self.list(Token::OpenBracket, Token::CloseBracket)
+    }
+
+    /// Parses a comma separated list of expressions between the given
+    /// brackets.
+    fn list(&mut self, open: Token, close: Token) -> ParseResult<Vec<Expression>> {
+        self.expect(open)?;
+        let mut items = Vec::new();
+        if self.peek_token() != Some(&close) {
+            loop {
+                items.push(self.single_expression()?);
+                if self.peek_token() == Some(&Token::Comma) {
+                    self.next_token();
+                } else {
+                    break;
+                }
+            }
+        }
+        self.expect(close)?;
+        Ok(items)
+    }
+
     /// Attempt to parse a single left denotation
     fn parse_led(&mut self, lhs: Expression) -> ParseResult<Expression> {
-        self.lexer
-            .next()
-            .map_or(Err(ParseError::Incomplete), |t| t.led(self, lhs))
+        match self.next_token() {
+            Some(t) => t.led(self, lhs),
+            None => Err(self.unexpected("an operator")),
+        }
     }
 
     /// Attempt to parse a single null denotation
     fn parse_nud(&mut self) -> ParseResult<Expression> {
-        self.lexer
-            .next()
-            .map_or(Err(ParseError::Incomplete), |t| t.nud(self))
+        match self.next_token() {
+            Some(t) => t.nud(self),
+            None => Err(self.unexpected("an expression")),
+        }
     }
 }
 
@@ -310,9 +891,18 @@ impl<'a> Token<'a> {
     /// of this token.
     fn lbp(&self) -> u32 {
         match *self {
+            // Conditional operator
+            Token::Question => 10,
+            // Logical operators
+            Token::PipePipe => 20,
+            Token::AmpAmp => 30,
+            // Comparison operators
+            Token::EqualEqual | Token::BangEqual => 40,
+            Token::Less | Token::LessEqual | Token::Greater | Token::GreaterEqual => 45,
             // Arithmetic operators
             Token::Plus | Token::Minus => 50,
             Token::Star | Token::Slash | Token::Percent => 60,
+            Token::Caret => 70,
             // Grouping operators
             Token::OpenBracket => 80,
             _ => 0,
@@ -331,16 +921,59 @@ impl<'a> Token<'a> {
             Token::Word("sgn") => parser.prefix_op(PrefixOp::Sgn),
             Token::Word("sin") => parser.prefix_op(PrefixOp::Sin),
             Token::Word("cos") => parser.prefix_op(PrefixOp::Cos),
-            Token::Word(word) => Ok(Expression::identifier(String::from(word))),
+            Token::Word("tan") => parser.prefix_op(PrefixOp::Tan),
+            Token::Word("rsin") => parser.prefix_op(PrefixOp::RawSin),
+            Token::Word("rcos") => parser.prefix_op(PrefixOp::RawCos),
+            Token::Word("rtan") => parser.prefix_op(PrefixOp::RawTan),
+            Token::Word("tanh") => parser.prefix_op(PrefixOp::Tanh),
+            Token::Word("exp") => parser.prefix_op(PrefixOp::Exp),
+            Token::Word("ln") => parser.prefix_op(PrefixOp::Ln),
+            Token::Word("log2") => parser.prefix_op(PrefixOp::Log2),
+            Token::Word("log10") => parser.prefix_op(PrefixOp::Log10),
+            Token::Word("sqrt") => parser.prefix_op(PrefixOp::Sqrt),
+            Token::Word("floor") => parser.prefix_op(PrefixOp::Floor),
+            Token::Word("ceil") => parser.prefix_op(PrefixOp::Ceil),
+            Token::Word("round") => parser.prefix_op(PrefixOp::Round),
+            Token::Word("fract") => parser.prefix_op(PrefixOp::Fract),
+            Token::Word("if") => parser.if_call(),
+            Token::Word("let") => parser.let_binding(),
+            Token::Word(word) if ARRAY_FUNCTIONS.contains(&word) => parser.array_call(word),
+            Token::Word(word) => match function_arity(word) {
+                Some(arity) => parser.call(word, arity),
+                // Calls to user-defined functions are checked once
+                // all of the functions are known.
+                None if parser.peek_token() == Some(&Token::OpenBracket) => {
+                    let args = parser.argument_list()?;
+                    Ok(Expression::call(String::from(word), args))
+                }
+                None => match constant(word) {
+                    Some(n) => Ok(Expression::constant_num(n)),
+                    None => Ok(Expression::identifier(String::from(word))),
+                },
+            },
             Token::Number(n) => Ok(Expression::constant_num(n)),
-            Token::Plus => parser.expression(100),
-            Token::Minus => parser.prefix_op(PrefixOp::Negate),
+            Token::Quantity(n, unit) => Ok(Expression::constant_num(unit.normalise(n))),
+            // A sign is part of a logarithmic quantity, so `-6db` is a
+            // gain of about 0.5 rather than -2.
+            Token::Plus | Token::Minus if parser.next_is_logarithmic() => {
+                let sign = if *self == Token::Minus { -1.0 } else { 1.0 };
+                match parser.next_token() {
+                    Some(Token::Quantity(n, unit)) => {
+                        Ok(Expression::constant_num(unit.normalise(sign * n)))
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            // Unary signs bind less tightly than `^`, so `-x^2` is `-(x^2)`
+            Token::Plus => parser.expression(65),
+            Token::Minus => parser.prefix_op_with_bp(PrefixOp::Negate, 65),
+            Token::Bang => parser.prefix_op(PrefixOp::Not),
             Token::OpenBracket => {
                 let expr = parser.single_expression()?;
                 parser.expect(Token::CloseBracket)?;
                 Ok(expr)
             }
-            _ => Err(ParseError::Unexpected),
+            _ => Err(parser.unexpected_token(self, "an expression")),
         }
     }
 
@@ -356,7 +989,29 @@ impl<'a> Token<'a> {
             Token::Star => self.infix(parser, lhs, InfixOp::Mul),
             Token::Slash => self.infix(parser, lhs, InfixOp::Div),
             Token::Percent => self.infix(parser, lhs, InfixOp::Mod),
-            _ => Err(ParseError::Incomplete),
+            // Exponentiation is right associative, so `2^3^2` is `2^(3^2)`
+            Token::Caret => {
+                let rhs = parser.expression(self.lbp() - 1)?;
+                Ok(Expression::infix(lhs, InfixOp::Pow, rhs))
+            }
+            Token::Less => self.infix(parser, lhs, InfixOp::Less),
+            Token::LessEqual => self.infix(parser, lhs, InfixOp::LessEqual),
+            Token::Greater => self.infix(parser, lhs, InfixOp::Greater),
+            Token::GreaterEqual => self.infix(parser, lhs, InfixOp::GreaterEqual),
+            Token::EqualEqual => self.infix(parser, lhs, InfixOp::Equal),
+            Token::BangEqual => self.infix(parser, lhs, InfixOp::NotEqual),
+            Token::AmpAmp => self.infix(parser, lhs, InfixOp::And),
+            Token::PipePipe => self.infix(parser, lhs, InfixOp::Or),
+            // Conditional operator
+            Token::Question => {
+                let then = parser.single_expression()?;
+                parser.expect(Token::Colon)?;
+                // Parse the else branch with a lower binding power so
+                // that chained conditionals associate to the right.
+                let otherwise = parser.expression(self.lbp() - 1)?;
+                Ok(Expression::conditional(lhs, then, otherwise))
+            }
+            _ => Err(parser.unexpected_token(self, "an operator")),
         }
     }
 
@@ -366,3 +1021,71 @@ impl<'a> Token<'a> {
         Ok(Expression::infix(lhs, op, rhs))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(n: f32) -> Expression {
+        Expression::constant_num(n)
+    }
+
+    fn var(name: &str) -> Expression {
+        Expression::identifier(String::from(name))
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        let expected = Expression::infix(
+            num(2.0),
+            InfixOp::Pow,
+            Expression::infix(num(3.0), InfixOp::Pow, num(2.0)),
+        );
+        assert_eq!(parse_single("2^3^2").unwrap(), expected);
+    }
+
+    #[test]
+    fn power_binds_tighter_than_negation() {
+        let expected = Expression::prefix(
+            PrefixOp::Negate,
+            Expression::infix(var("x"), InfixOp::Pow, num(2.0)),
+        );
+        assert_eq!(parse_single("-x^2").unwrap(), expected);
+    }
+
+    #[test]
+    fn power_accepts_negative_exponent() {
+        let expected = Expression::infix(
+            num(2.0),
+            InfixOp::Pow,
+            Expression::prefix(PrefixOp::Negate, num(1.0)),
+        );
+        assert_eq!(parse_single("2^-1").unwrap(), expected);
+    }
+
+    #[test]
+    fn power_binds_tighter_than_multiplication() {
+        let expected = Expression::infix(
+            var("a"),
+            InfixOp::Mul,
+            Expression::infix(var("b"), InfixOp::Pow, var("c")),
+        );
+        assert_eq!(parse_single("a*b^c").unwrap(), expected);
+    }
+
+    #[test]
+    fn negated_power_folds() {
+        assert_eq!(optimise(parse_single("-2^2").unwrap()), num(-4.0));
+    }
+
+    #[test]
+    fn constants_cannot_be_rebound() {
+        assert_eq!(
+            parse_single("let e = 2; e*x"),
+            Err(ParseError::Constant {
+                position: 4,
+                name: String::from("e"),
+            })
+        );
+    }
+}